@@ -0,0 +1,648 @@
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use thiserror::Error;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::{Stream, StreamExt};
+use tonic::codec::CompressionEncoding;
+use tonic::metadata::MetadataValue;
+use tonic::{transport::Channel, Code, Request, Status};
+use tracing::{debug, info};
+use url::Url;
+
+use crate::md5rs::md5rs_client::Md5rsClient;
+use crate::md5rs::{
+    AuthRequest, Bbox, DetectBatchRequest, DetectRequest, DetectResponse, ServerInfoRequest,
+    ServerInfoResponse,
+};
+use crate::media::{Encoding, FrameFormat};
+use crate::proxy::{Proxy, ProxyConnector};
+use crate::tls::{self, ChannelSecurity, PinnedConnector};
+use crate::Config;
+
+pub type RequestStream = Pin<Box<dyn Stream<Item = DetectRequest> + Send>>;
+pub type ResponseStream = Pin<Box<dyn Stream<Item = Result<DetectResponse, Status>> + Send>>;
+
+/// Status metadata holding the seconds until an exhausted quota resets.
+pub const RETRY_AFTER: &str = "retry-after";
+
+/// The server refused work because the token's daily quota is used up.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("Daily quota exceeded")]
+pub struct QuotaExceeded {
+    /// Time until the quota resets, if the server said.
+    pub resets_in: Option<Duration>,
+}
+
+impl QuotaExceeded {
+    /// Recognize a `RESOURCE_EXHAUSTED` status, reading the reset time from
+    /// its `retry-after` metadata.
+    pub fn from_status(status: &Status) -> Option<Self> {
+        if status.code() != Code::ResourceExhausted {
+            return None;
+        }
+        let resets_in = status
+            .metadata()
+            .get(RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok())
+            .map(Duration::from_secs);
+        Some(Self { resets_in })
+    }
+
+    /// The status a server reports an exhausted quota with.
+    pub fn to_status(&self) -> Status {
+        let mut status = Status::resource_exhausted("Daily quota exceeded");
+        if let Some(resets_in) = self.resets_in {
+            status
+                .metadata_mut()
+                .insert(RETRY_AFTER, resets_in.as_secs().into());
+        }
+        status
+    }
+}
+
+/// Longest frame side sent to servers that do not report one.
+pub const DEFAULT_IMGSZ: usize = 1280;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum NegotiationError {
+    #[error("{name} {value} is outside the range {min}..={max} accepted by {endpoint}")]
+    OutOfRange {
+        name: &'static str,
+        value: f32,
+        min: f32,
+        max: f32,
+        endpoint: String,
+    },
+
+    #[error("No image encoding is accepted by every server, the client can send webp or jpeg")]
+    NoCommonEncoding,
+}
+
+/// Check `iou` and `conf` against the ranges each server accepts, then pick
+/// the largest frame size and the preferred encoding all of them take.
+/// Servers without `ServerInfo` are taken to accept 1280 pixel WebP frames.
+pub fn negotiate(
+    servers: &[(String, Option<ServerInfoResponse>)],
+    iou: f32,
+    conf: f32,
+) -> Result<FrameFormat, NegotiationError> {
+    let mut imgsz = None;
+    let mut encodings = Encoding::ALL.to_vec();
+    for (endpoint, info) in servers {
+        let default = ServerInfoResponse::default();
+        let info = info.as_ref().unwrap_or(&default);
+        check_range("iou", iou, (info.min_iou, info.max_iou), endpoint)?;
+        check_range("conf", conf, (info.min_score, info.max_score), endpoint)?;
+        let max_imgsz = usize::try_from(info.max_imgsz)
+            .ok()
+            .filter(|size| *size > 0)
+            .unwrap_or(DEFAULT_IMGSZ);
+        imgsz = Some(imgsz.map_or(max_imgsz, |size: usize| size.min(max_imgsz)));
+        if info.encodings.is_empty() {
+            encodings.retain(|e| *e == Encoding::Webp);
+        } else {
+            encodings.retain(|e| info.encodings.iter().any(|name| name == e.name()));
+        }
+    }
+    Ok(FrameFormat {
+        imgsz: imgsz.unwrap_or(DEFAULT_IMGSZ),
+        encoding: *encodings
+            .first()
+            .ok_or(NegotiationError::NoCommonEncoding)?,
+    })
+}
+
+fn check_range(
+    name: &'static str,
+    value: f32,
+    (min, max): (f32, f32),
+    endpoint: &str,
+) -> Result<(), NegotiationError> {
+    let (min, max) = if min == 0.0 && max == 0.0 {
+        (0.0, 1.0)
+    } else {
+        (min, max)
+    };
+    if (min..=max).contains(&value) {
+        Ok(())
+    } else {
+        Err(NegotiationError::OutOfRange {
+            name,
+            value,
+            min,
+            max,
+            endpoint: endpoint.to_string(),
+        })
+    }
+}
+
+/// The model and server version recorded with each answered frame, e.g.
+/// `MegaDetector v5a (md5rs-server 0.2.0)`.
+pub fn model_description(info: &ServerInfoResponse) -> String {
+    let model = [info.model.as_str(), info.model_version.as_str()]
+        .into_iter()
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ");
+    match (model.is_empty(), info.server_version.is_empty()) {
+        (_, true) => model,
+        (true, false) => format!("md5rs-server {}", info.server_version),
+        (false, false) => format!("{} (md5rs-server {})", model, info.server_version),
+    }
+}
+
+/// A session token and when it should be refreshed.
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub token: String,
+    pub refresh_at: Option<Instant>,
+}
+
+impl Session {
+    /// A session expiring after `expires_in`, refreshed a minute early (or
+    /// halfway through for short sessions).
+    pub fn new(token: String, expires_in: Option<Duration>) -> Self {
+        let refresh_at =
+            expires_in.map(|ttl| Instant::now() + ttl - (ttl / 2).min(Duration::from_secs(60)));
+        Self { token, refresh_at }
+    }
+}
+
+/// Something that can authenticate a token and turn a stream of frames into
+/// a stream of detections.
+#[tonic::async_trait]
+pub trait DetectionBackend: Send {
+    /// Exchange an access token for a session.
+    async fn auth(&mut self, token: &str) -> Result<Session>;
+
+    /// Name of the server in logs.
+    fn endpoint(&self) -> String;
+
+    /// What the server runs and accepts, `None` for servers predating
+    /// `ServerInfo`.
+    async fn server_info(&mut self) -> Result<Option<ServerInfoResponse>>;
+
+    /// Frames sent per message once `server_info` has been asked, so streams
+    /// keep enough in flight to fill a batch.
+    fn batch_size(&self) -> usize {
+        1
+    }
+
+    /// Open a detection stream authorized by `session_token`.
+    async fn detect(
+        &mut self,
+        session_token: &str,
+        requests: RequestStream,
+    ) -> Result<ResponseStream, Status>;
+}
+
+/// Compression of gRPC messages in both directions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl From<Compression> for CompressionEncoding {
+    fn from(c: Compression) -> Self {
+        match c {
+            Compression::Gzip => CompressionEncoding::Gzip,
+            Compression::Zstd => CompressionEncoding::Zstd,
+        }
+    }
+}
+
+/// The md5rs-server gRPC client. Clones share the connection.
+#[derive(Clone)]
+pub struct GrpcBackend {
+    url: String,
+    client: Md5rsClient<Channel>,
+    batch_size: usize,
+    batch_latency: Duration,
+    /// Largest batch the server takes, 0 until `server_info` reports one.
+    max_batch: usize,
+}
+
+impl GrpcBackend {
+    pub async fn connect(config: &Config, url: &str) -> Result<Self> {
+        let security = tls::channel_security(config, url)?;
+        let endpoint = match &security {
+            // The connector runs TLS, tonic only sees a plaintext connection
+            // but still sends https requests.
+            ChannelSecurity::Pinned(_) => {
+                Channel::from_shared(tls::pinned_endpoint_uri(url)?)?.origin(url.parse()?)
+            }
+            _ => Channel::from_shared(url.to_string())?,
+        };
+        let mut endpoint = endpoint
+            .initial_stream_window_size(config.stream_window)
+            .initial_connection_window_size(config.connection_window);
+        if let Some(timeout) = config.connect_timeout {
+            endpoint = endpoint.connect_timeout(timeout);
+        }
+        if let Some(interval) = config.keepalive {
+            endpoint = endpoint
+                .http2_keep_alive_interval(interval)
+                .keep_alive_while_idle(true);
+        }
+        let proxy = Proxy::for_target(config.proxy.as_deref(), &Url::parse(url)?)?;
+        if let Some(proxy) = &proxy {
+            info!(
+                "Connecting to {} through proxy {}:{}",
+                url, proxy.host, proxy.port
+            );
+        }
+        let channel = match (security, proxy) {
+            (ChannelSecurity::Pinned(tls), proxy) => {
+                endpoint
+                    .connect_with_connector(PinnedConnector::new(tls, url, proxy)?)
+                    .await?
+            }
+            (security, proxy) => {
+                if let ChannelSecurity::Tls(tls) = security {
+                    endpoint = endpoint.tls_config(tls)?;
+                }
+                match proxy {
+                    Some(proxy) => {
+                        endpoint
+                            .connect_with_connector(ProxyConnector::new(proxy))
+                            .await?
+                    }
+                    None => endpoint.connect().await?,
+                }
+            }
+        };
+
+        let mut client = Md5rsClient::new(channel);
+        if let Some(compression) = config.compression {
+            client = client
+                .send_compressed(compression.into())
+                .accept_compressed(compression.into());
+        }
+
+        Ok(Self {
+            url: url.to_string(),
+            client,
+            // A batch can never fill beyond the frames allowed in flight.
+            batch_size: config.batch_size.min(config.max_in_flight.max(1)),
+            batch_latency: config.batch_latency,
+            max_batch: 0,
+        })
+    }
+}
+
+#[tonic::async_trait]
+impl DetectionBackend for GrpcBackend {
+    async fn auth(&mut self, token: &str) -> Result<Session> {
+        let response = self
+            .client
+            .auth(Request::new(AuthRequest {
+                token: token.to_string(),
+            }))
+            .await
+            .map_err(|status| match QuotaExceeded::from_status(&status) {
+                Some(exceeded) => anyhow::Error::from(exceeded),
+                None => status.into(),
+            })?;
+        let auth_response = response.into_inner();
+        if auth_response.success {
+            let expires_in = u64::try_from(auth_response.expires_in)
+                .ok()
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs);
+            Ok(Session::new(auth_response.token, expires_in))
+        } else {
+            Err(anyhow::anyhow!("Auth failed"))
+        }
+    }
+
+    fn endpoint(&self) -> String {
+        self.url.clone()
+    }
+
+    async fn server_info(&mut self) -> Result<Option<ServerInfoResponse>> {
+        match self.client.server_info(ServerInfoRequest {}).await {
+            Ok(response) => {
+                let info = response.into_inner();
+                self.max_batch = usize::try_from(info.max_batch).unwrap_or(0);
+                Ok(Some(info))
+            }
+            Err(status) if status.code() == Code::Unimplemented => Ok(None),
+            Err(status) => Err(status.into()),
+        }
+    }
+
+    fn batch_size(&self) -> usize {
+        self.batch_size.min(self.max_batch).max(1)
+    }
+
+    async fn detect(
+        &mut self,
+        session_token: &str,
+        requests: RequestStream,
+    ) -> Result<ResponseStream, Status> {
+        let session_token: MetadataValue<_> = session_token
+            .parse()
+            .map_err(|_| Status::invalid_argument("Invalid session token"))?;
+        let batch_size = self.batch_size();
+        if batch_size <= 1 {
+            let mut request = Request::new(forward(requests));
+            request
+                .metadata_mut()
+                .insert("authorization", session_token);
+            let response = self.client.detect(request).await?;
+            return Ok(Box::pin(response.into_inner()));
+        }
+
+        debug!(
+            "Sending batches of up to {} frames to {}",
+            batch_size, self.url
+        );
+        // Send whatever has arrived once the latency budget is spent, so a
+        // slow trickle of frames is not held back waiting for a full batch.
+        let batches = requests
+            .chunks_timeout(batch_size, self.batch_latency)
+            .map(|frames| DetectBatchRequest { frames });
+        let mut request = Request::new(forward(Box::pin(batches)));
+        request
+            .metadata_mut()
+            .insert("authorization", session_token);
+        let mut inbound = self.client.detect_batch(request).await?.into_inner();
+        Ok(Box::pin(async_stream::stream! {
+            while let Some(batch) = inbound.next().await {
+                match batch {
+                    Ok(batch) => {
+                        for result in batch.results {
+                            yield Ok(result);
+                        }
+                    }
+                    Err(status) => {
+                        yield Err(status);
+                        break;
+                    }
+                }
+            }
+        }))
+    }
+}
+
+/// Feed a boxed stream through a channel so tonic sees a concrete stream type;
+/// handing it the trait object directly trips a higher-ranked lifetime error
+/// inside `async_trait`.
+fn forward<T: Send + 'static>(
+    mut requests: Pin<Box<dyn Stream<Item = T> + Send>>,
+) -> ReceiverStream<T> {
+    let (tx, rx) = tokio::sync::mpsc::channel(1);
+    tokio::spawn(async move {
+        while let Some(request) = requests.next().await {
+            if tx.send(request).await.is_err() {
+                break;
+            }
+        }
+    });
+    ReceiverStream::new(rx)
+}
+
+/// An in-process backend that answers every frame with the same detections,
+/// for running the pipeline without a server.
+#[derive(Clone)]
+pub struct FakeBackend {
+    token: Option<String>,
+    label: Vec<String>,
+    bboxes: Vec<Bbox>,
+    disconnect_after: Option<usize>,
+    expire_after: Option<usize>,
+    session_ttl: Option<Duration>,
+    quota: Option<(usize, QuotaExceeded)>,
+    ignore_first: usize,
+    server_info: Option<ServerInfoResponse>,
+    streams: Arc<AtomicUsize>,
+    received: Arc<AtomicUsize>,
+    answered: Arc<AtomicUsize>,
+}
+
+impl Default for FakeBackend {
+    fn default() -> Self {
+        Self {
+            token: None,
+            label: vec!["Blank".to_string()],
+            bboxes: Vec::new(),
+            disconnect_after: None,
+            expire_after: None,
+            session_ttl: None,
+            quota: None,
+            ignore_first: 0,
+            server_info: None,
+            streams: Arc::new(AtomicUsize::new(0)),
+            received: Arc::new(AtomicUsize::new(0)),
+            answered: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+}
+
+impl FakeBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Answer `ServerInfo` with `info` instead of as a server predating it.
+    pub fn with_server_info(mut self, info: ServerInfoResponse) -> Self {
+        self.server_info = Some(info);
+        self
+    }
+
+    /// Only accept this access token.
+    pub fn with_token(mut self, token: &str) -> Self {
+        self.token = Some(token.to_string());
+        self
+    }
+
+    /// Answer every frame with these detections.
+    pub fn with_detections(mut self, label: Vec<String>, bboxes: Vec<Bbox>) -> Self {
+        self.label = label;
+        self.bboxes = bboxes;
+        self
+    }
+
+    /// Fail the first stream with `UNAVAILABLE` after answering `n` frames.
+    pub fn disconnect_after(mut self, n: usize) -> Self {
+        self.disconnect_after = Some(n);
+        self
+    }
+
+    /// Fail the first stream with `UNAUTHENTICATED` after answering `n` frames.
+    pub fn expire_after(mut self, n: usize) -> Self {
+        self.expire_after = Some(n);
+        self
+    }
+
+    /// Hand out sessions that expire after `ttl`.
+    pub fn with_session_ttl(mut self, ttl: Duration) -> Self {
+        self.session_ttl = Some(ttl);
+        self
+    }
+
+    /// Answer `frames` frames in total, then report an exhausted quota that
+    /// resets after `resets_in`.
+    pub fn with_quota(mut self, frames: usize, resets_in: Duration) -> Self {
+        self.quota = Some((
+            frames,
+            QuotaExceeded {
+                resets_in: Some(resets_in),
+            },
+        ));
+        self
+    }
+
+    /// Never answer the first `n` frames received.
+    pub fn ignore_first(mut self, n: usize) -> Self {
+        self.ignore_first = n;
+        self
+    }
+
+    fn quota_exceeded(&self) -> Option<QuotaExceeded> {
+        self.quota
+            .as_ref()
+            .filter(|(frames, _)| self.answered.load(Ordering::SeqCst) >= *frames)
+            .map(|(_, exceeded)| exceeded.clone())
+    }
+
+    /// Number of detection streams opened so far.
+    pub fn streams_opened(&self) -> usize {
+        self.streams.load(Ordering::SeqCst)
+    }
+
+    /// Number of frames received so far, including those sent again.
+    pub fn frames_received(&self) -> usize {
+        self.received.load(Ordering::SeqCst)
+    }
+}
+
+#[tonic::async_trait]
+impl DetectionBackend for FakeBackend {
+    async fn auth(&mut self, token: &str) -> Result<Session> {
+        if let Some(exceeded) = self.quota_exceeded() {
+            return Err(exceeded.into());
+        }
+        match &self.token {
+            Some(expected) if expected != token => Err(anyhow::anyhow!("Auth failed")),
+            _ => Ok(Session::new(format!("session-{}", token), self.session_ttl)),
+        }
+    }
+
+    fn endpoint(&self) -> String {
+        "fake".to_string()
+    }
+
+    async fn server_info(&mut self) -> Result<Option<ServerInfoResponse>> {
+        Ok(self.server_info.clone())
+    }
+
+    async fn detect(
+        &mut self,
+        session_token: &str,
+        requests: RequestStream,
+    ) -> Result<ResponseStream, Status> {
+        if !session_token.starts_with("session-") {
+            return Err(Status::unauthenticated("Invalid session token"));
+        }
+        let stream_index = self.streams.fetch_add(1, Ordering::SeqCst);
+        let disconnect_after = self.disconnect_after.filter(|_| stream_index == 0);
+        let expire_after = self.expire_after.filter(|_| stream_index == 0);
+        let label = self.label.clone();
+        let bboxes = self.bboxes.clone();
+        let fake = self.clone();
+        let responses = async_stream::stream! {
+            let mut requests = requests;
+            let mut answered = 0;
+            while let Some(request) = requests.next().await {
+                if let Some(exceeded) = fake.quota_exceeded() {
+                    yield Err(exceeded.to_status());
+                    break;
+                }
+                if fake.received.fetch_add(1, Ordering::SeqCst) < fake.ignore_first {
+                    continue;
+                }
+                if disconnect_after.is_some_and(|n| answered >= n) {
+                    yield Err(Status::unavailable("Fake backend disconnected"));
+                    break;
+                }
+                if expire_after.is_some_and(|n| answered >= n) {
+                    yield Err(Status::unauthenticated("Session expired"));
+                    break;
+                }
+                answered += 1;
+                fake.answered.fetch_add(1, Ordering::SeqCst);
+                yield Ok(DetectResponse {
+                    uuid: request.uuid,
+                    label: label.clone(),
+                    bboxs: bboxes.clone(),
+                });
+            }
+        };
+        Ok(Box::pin(responses))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate_with_servers() {
+        let jpeg_only = ServerInfoResponse {
+            max_imgsz: 640,
+            encodings: vec!["jpeg".to_string()],
+            min_score: 0.1,
+            max_score: 0.9,
+            ..Default::default()
+        };
+        let both = ServerInfoResponse {
+            max_imgsz: 1600,
+            encodings: vec!["jpeg".to_string(), "webp".to_string()],
+            ..Default::default()
+        };
+        let servers = vec![
+            ("a".to_string(), Some(jpeg_only.clone())),
+            ("b".to_string(), Some(both)),
+        ];
+        assert_eq!(
+            negotiate(&servers, 0.45, 0.2),
+            Ok(FrameFormat {
+                imgsz: 640,
+                encoding: Encoding::Jpeg
+            })
+        );
+        assert_eq!(
+            negotiate(&servers, 0.45, 0.05),
+            Err(NegotiationError::OutOfRange {
+                name: "conf",
+                value: 0.05,
+                min: 0.1,
+                max: 0.9,
+                endpoint: "a".to_string(),
+            })
+        );
+        assert!(negotiate(&servers[1..], 1.5, 0.2).is_err());
+
+        // Servers predating ServerInfo only take WebP.
+        let legacy = vec![("c".to_string(), None)];
+        assert_eq!(
+            negotiate(&legacy, 0.45, 0.2),
+            Ok(FrameFormat {
+                imgsz: DEFAULT_IMGSZ,
+                encoding: Encoding::Webp
+            })
+        );
+        let mixed = vec![("a".to_string(), Some(jpeg_only)), legacy[0].clone()];
+        assert_eq!(
+            negotiate(&mixed, 0.45, 0.2),
+            Err(NegotiationError::NoCommonEncoding)
+        );
+    }
+}
@@ -0,0 +1,93 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::Parser;
+use tonic::transport::Identity;
+
+use md5rs_client::log;
+use md5rs_client::mock::{load_script, serve, MockConfig};
+
+/// A local md5rs server for integration tests, demos and capacity tests.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    #[arg(short, long, default_value = "127.0.0.1:50051")]
+    addr: SocketAddr,
+    /// Accepted access token, may be repeated. Any token is accepted if none is given.
+    #[arg(short, long)]
+    token: Vec<String>,
+    /// Access token that fails `Auth` with an exhausted quota, may be repeated.
+    #[arg(long)]
+    exhausted_token: Vec<String>,
+    /// Frames each token may submit before `Detect` reports an exhausted quota.
+    #[arg(long)]
+    quota: Option<usize>,
+    /// Delay before each response in milliseconds.
+    #[arg(long, default_value_t = 0)]
+    latency_ms: u64,
+    /// Fail every Detect stream with UNAVAILABLE after this many responses.
+    #[arg(long)]
+    fail_after: Option<usize>,
+    /// Lifetime of session tokens in seconds.
+    #[arg(long)]
+    session_ttl: Option<u64>,
+    /// Longest frame side reported to clients.
+    #[arg(long)]
+    imgsz: Option<u32>,
+    /// Image encoding accepted, may be repeated. webp and jpeg if none is given.
+    #[arg(long)]
+    encoding: Vec<String>,
+    /// Most frames per DetectBatch request, 0 to disable DetectBatch.
+    #[arg(long)]
+    max_batch: Option<usize>,
+    /// JSON file with responses to return in turn instead of the deterministic ones.
+    #[arg(long)]
+    script: Option<PathBuf>,
+    /// PEM certificate to serve TLS with, requires --tls-key.
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key to serve TLS with.
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    #[arg(long, default_value = "info")]
+    log_level: String,
+    #[arg(long, default_value = "md5rs-mock-server.log")]
+    log_file: String,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), anyhow::Error> {
+    let args = Args::parse();
+
+    let _guard =
+        log::init_logger(args.log_level, args.log_file, Vec::new()).expect("Failed to init logger");
+
+    let script = match &args.script {
+        Some(path) => load_script(path)?,
+        None => Vec::new(),
+    };
+
+    let config = MockConfig {
+        tokens: args.token.into_iter().collect(),
+        exhausted_tokens: args.exhausted_token.into_iter().collect(),
+        quota: args.quota,
+        latency: Duration::from_millis(args.latency_ms),
+        fail_after: args.fail_after,
+        script,
+        session_ttl: args.session_ttl.map(Duration::from_secs),
+        imgsz: args.imgsz,
+        encodings: args.encoding,
+        max_batch: args.max_batch,
+    };
+
+    let identity = match (args.tls_cert, args.tls_key) {
+        (Some(cert), Some(key)) => Some(Identity::from_pem(
+            std::fs::read(cert)?,
+            std::fs::read(key)?,
+        )),
+        _ => None,
+    };
+
+    serve(args.addr, config, identity).await
+}
@@ -0,0 +1,418 @@
+use std::fmt::Write as _;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use image::DynamicImage;
+use tokio_stream::StreamExt;
+use url::Url;
+use uuid::Uuid;
+
+use crate::backend::{self, DetectionBackend, GrpcBackend, QuotaExceeded};
+use crate::md5rs::{DetectRequest, ServerInfoResponse};
+use crate::proxy::Proxy;
+use crate::{tls, Config};
+
+/// Longest any single network check may take.
+const STEP_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Pass,
+    Fail,
+    Skip,
+}
+
+/// The outcome of one diagnostic step.
+#[derive(Debug, Clone)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: Status,
+    pub latency: Option<Duration>,
+    pub detail: String,
+    /// What to try when the check failed.
+    pub fix: Option<String>,
+}
+
+impl Check {
+    fn pass(name: &'static str, latency: Duration, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: Status::Pass,
+            latency: Some(latency),
+            detail: detail.into(),
+            fix: None,
+        }
+    }
+
+    fn fail(name: &'static str, latency: Option<Duration>, detail: String, fix: &str) -> Self {
+        Self {
+            name,
+            status: Status::Fail,
+            latency,
+            detail,
+            fix: Some(fix.to_string()),
+        }
+    }
+
+    fn skip(name: &'static str, detail: &str) -> Self {
+        Self {
+            name,
+            status: Status::Skip,
+            latency: None,
+            detail: detail.to_string(),
+            fix: None,
+        }
+    }
+}
+
+/// Walk through everything a run needs from `url`: name resolution, the TLS
+/// chain, `ServerInfo`, `Auth`, one synthetic frame through `Detect`, and ffmpeg. Steps
+/// after a failed network step are skipped.
+///
+/// The synthetic frame counts against the token's daily quota.
+pub async fn run(config: &Config, url: &str) -> Vec<Check> {
+    let mut checks = Vec::new();
+    let failed = |checks: &Vec<Check>| checks.iter().any(|c| c.status == Status::Fail);
+
+    checks.push(resolve(config, url).await);
+
+    checks.push(if failed(&checks) {
+        Check::skip("TLS", "skipped after a failed check")
+    } else if url.starts_with("http://") {
+        Check::skip("TLS", "plaintext http:// server")
+    } else {
+        verify_chain(config, url).await
+    });
+
+    let mut backend = None;
+    checks.push(if failed(&checks) {
+        Check::skip("Connect", "skipped after a failed check")
+    } else {
+        match timed(GrpcBackend::connect(config, url)).await {
+            (elapsed, Ok(connected)) => {
+                backend = Some(connected);
+                Check::pass("Connect", elapsed, "gRPC channel open")
+            }
+            (elapsed, Err(e)) => Check::fail(
+                "Connect",
+                Some(elapsed),
+                format!("{:#}", e),
+                "Check that the server is up and that a firewall or proxy (--proxy, HTTPS_PROXY) lets gRPC through",
+            ),
+        }
+    });
+
+    match backend {
+        Some(mut backend) => checks.extend(exchange(config, &mut backend).await),
+        None => {
+            for name in ["ServerInfo", "Auth", "Detect"] {
+                checks.push(Check::skip(name, "skipped after a failed check"));
+            }
+        }
+    }
+
+    checks.push(check_ffmpeg().await);
+    checks
+}
+
+async fn resolve(config: &Config, url: &str) -> Check {
+    let start = Instant::now();
+    let target = match Url::parse(url) {
+        Ok(target) => target,
+        Err(e) => {
+            return Check::fail(
+                "DNS",
+                None,
+                format!("Invalid URL {}: {}", url, e),
+                "Pass the server as https://host[:port]",
+            )
+        }
+    };
+    // Through a proxy only the proxy's name has to resolve here.
+    let proxy = match Proxy::for_target(config.proxy.as_deref(), &target) {
+        Ok(proxy) => proxy,
+        Err(e) => {
+            return Check::fail(
+                "DNS",
+                None,
+                format!("{:#}", e),
+                "Fix --proxy or the HTTPS_PROXY/ALL_PROXY environment variables",
+            )
+        }
+    };
+    let (host, port) = match &proxy {
+        Some(proxy) => (proxy.host.clone(), proxy.port),
+        None => (
+            target.host_str().unwrap_or_default().to_string(),
+            target.port_or_known_default().unwrap_or(443),
+        ),
+    };
+    match tokio::time::timeout(STEP_TIMEOUT, tokio::net::lookup_host((host.clone(), port))).await {
+        Ok(Ok(addrs)) => {
+            let addrs: Vec<String> = addrs.map(|a| a.ip().to_string()).collect();
+            let via = if proxy.is_some() { " (proxy)" } else { "" };
+            Check::pass(
+                "DNS",
+                start.elapsed(),
+                format!("{}{} -> {}", host, via, addrs.join(", ")),
+            )
+        }
+        Ok(Err(e)) => Check::fail(
+            "DNS",
+            Some(start.elapsed()),
+            format!("{}: {}", host, e),
+            "Check the server name and the network connection or DNS settings",
+        ),
+        Err(_) => Check::fail(
+            "DNS",
+            Some(start.elapsed()),
+            format!("{}: timed out", host),
+            "Check the network connection or DNS settings",
+        ),
+    }
+}
+
+async fn verify_chain(config: &Config, url: &str) -> Check {
+    let start = Instant::now();
+    let (config, url) = (config.clone(), url.to_string());
+    let verified = tokio::task::spawn_blocking(move || tls::verify_server_chain(&config, &url));
+    match tokio::time::timeout(STEP_TIMEOUT, verified).await {
+        Ok(Ok(Ok(chain))) => Check::pass(
+            "TLS",
+            start.elapsed(),
+            format!(
+                "{} certificates, leaf {}",
+                chain.len(),
+                chain.first().map(|f| f.spki.as_str()).unwrap_or_default()
+            ),
+        ),
+        Ok(Ok(Err(e))) => Check::fail(
+            "TLS",
+            Some(start.elapsed()),
+            format!("{:#}", e),
+            "Check the system clock, pass --ca-file for private CAs, and update --pin after certificate rotations",
+        ),
+        Ok(Err(e)) => Check::fail("TLS", None, e.to_string(), "Report this as a bug"),
+        Err(_) => Check::fail(
+            "TLS",
+            Some(start.elapsed()),
+            "Handshake timed out".to_string(),
+            "Check that the port is open and that a firewall or proxy lets TLS through",
+        ),
+    }
+}
+
+/// `ServerInfo`, `Auth` and a synthetic `Detect` round trip.
+async fn exchange(config: &Config, backend: &mut GrpcBackend) -> Vec<Check> {
+    let (elapsed, info) = match timed(backend.server_info()).await {
+        (elapsed, Ok(info)) => (elapsed, info),
+        (elapsed, Err(e)) => {
+            return vec![
+                Check::fail(
+                    "ServerInfo",
+                    Some(elapsed),
+                    format!("{:#}", e),
+                    "Check that the URL points at an md5rs server of a version this client supports",
+                ),
+                Check::skip("Auth", "skipped after a failed check"),
+                Check::skip("Detect", "skipped after a failed check"),
+            ];
+        }
+    };
+    let model = info
+        .as_ref()
+        .map(backend::model_description)
+        .unwrap_or_else(|| "model not reported".to_string());
+    let mut checks = vec![Check::pass("ServerInfo", elapsed, model)];
+
+    let (elapsed, session) = match timed(backend.auth(&config.token)).await {
+        (elapsed, Ok(session)) => (elapsed, session),
+        (elapsed, Err(e)) => {
+            let fix = match e.downcast_ref::<QuotaExceeded>() {
+                Some(_) => "Wait for the daily quota to reset or use another access token",
+                None => {
+                    "Check the access token (MD5RS_TOKEN, --token-file, the config profile or -t)"
+                }
+            };
+            checks.push(Check::fail("Auth", Some(elapsed), format!("{:#}", e), fix));
+            checks.push(Check::skip("Detect", "skipped after a failed check"));
+            return checks;
+        }
+    };
+    checks.push(Check::pass("Auth", elapsed, "token accepted"));
+    checks.push(detect_one(config, backend, &session.token, info).await);
+    checks
+}
+
+async fn detect_one(
+    config: &Config,
+    backend: &mut GrpcBackend,
+    session_token: &str,
+    info: Option<ServerInfoResponse>,
+) -> Check {
+    let servers = vec![(backend.endpoint(), info)];
+    let format = match backend::negotiate(&servers, config.iou, config.conf) {
+        Ok(format) => format,
+        Err(e) => {
+            return Check::fail(
+                "Detect",
+                None,
+                e.to_string(),
+                "Pass --iou and --conf within the range the server accepts",
+            )
+        }
+    };
+    let frame = DynamicImage::new_rgb8(64, 48);
+    let image = match format.encoding.encode(&frame, config.quality) {
+        Ok(image) => image,
+        Err(e) => return Check::fail("Detect", None, format!("{:#}", e), "Report this as a bug"),
+    };
+    let uuid = Uuid::new_v4().to_string();
+    let request = DetectRequest {
+        uuid: uuid.clone(),
+        image,
+        width: 64,
+        height: 48,
+        iou: config.iou,
+        score: config.conf,
+        encoding: format.encoding.name().to_string(),
+    };
+
+    let start = Instant::now();
+    let round_trip = async {
+        let mut inbound = backend
+            .detect(session_token, Box::pin(tokio_stream::iter([request])))
+            .await?;
+        match inbound.next().await {
+            Some(response) => response,
+            None => Err(tonic::Status::unavailable(
+                "Stream closed without an answer",
+            )),
+        }
+    };
+    match tokio::time::timeout(config.frame_timeout.min(STEP_TIMEOUT), round_trip).await {
+        Ok(Ok(response)) if response.uuid == uuid => Check::pass(
+            "Detect",
+            start.elapsed(),
+            format!("synthetic frame answered as {:?}", response.label),
+        ),
+        Ok(Ok(response)) => Check::fail(
+            "Detect",
+            Some(start.elapsed()),
+            format!("answered {} instead of {}", response.uuid, uuid),
+            "Report this to the server operator",
+        ),
+        Ok(Err(status)) => {
+            let fix = match QuotaExceeded::from_status(&status) {
+                Some(_) => "Wait for the daily quota to reset or use another access token",
+                None => "Check the server log; if runs fail too, run them without --compression and with --batch-size 1",
+            };
+            Check::fail(
+                "Detect",
+                Some(start.elapsed()),
+                format!("{}: {}", status.code(), status.message()),
+                fix,
+            )
+        }
+        Err(_) => Check::fail(
+            "Detect",
+            Some(start.elapsed()),
+            "No answer to the synthetic frame".to_string(),
+            "The server may be overloaded; try again later, and give runs a longer --frame-timeout",
+        ),
+    }
+}
+
+async fn check_ffmpeg() -> Check {
+    let start = Instant::now();
+    let version = tokio::task::spawn_blocking(ffmpeg_sidecar::version::ffmpeg_version).await;
+    match version {
+        Ok(Ok(version)) => Check::pass("ffmpeg", start.elapsed(), format!("ffmpeg {}", version)),
+        Ok(Err(e)) => Check::fail(
+            "ffmpeg",
+            None,
+            format!(
+                "Failed to run {}: {:#}",
+                ffmpeg_sidecar::paths::ffmpeg_path().display(),
+                e
+            ),
+            "Place ffmpeg next to md5rs-client or on PATH; it is only needed for videos",
+        ),
+        Err(e) => Check::fail("ffmpeg", None, e.to_string(), "Report this as a bug"),
+    }
+}
+
+/// Run a network step with `STEP_TIMEOUT`, returning how long it took.
+async fn timed<T>(step: impl Future<Output = Result<T>>) -> (Duration, Result<T>) {
+    let start = Instant::now();
+    let result = match tokio::time::timeout(STEP_TIMEOUT, step).await {
+        Ok(result) => result,
+        Err(_) => Err(anyhow::anyhow!("Timed out after {:?}", STEP_TIMEOUT)),
+    };
+    (start.elapsed(), result)
+}
+
+/// Render checks as a table followed by suggested fixes for the failures.
+pub fn render(checks: &[Check]) -> String {
+    let name_width = checks.iter().map(|c| c.name.len()).max().unwrap_or(0);
+    let mut table = String::new();
+    for check in checks {
+        let status = match check.status {
+            Status::Pass => "PASS",
+            Status::Fail => "FAIL",
+            Status::Skip => "SKIP",
+        };
+        let latency = check
+            .latency
+            .map(|l| format!("{} ms", l.as_millis()))
+            .unwrap_or_default();
+        let _ = writeln!(
+            table,
+            "{:<name_width$}  {}  {:>8}  {}",
+            check.name, status, latency, check.detail
+        );
+    }
+    let fixes: Vec<&Check> = checks.iter().filter(|c| c.fix.is_some()).collect();
+    if !fixes.is_empty() {
+        let _ = writeln!(table, "\nSuggested fixes:");
+        for check in fixes {
+            let _ = writeln!(table, "  {}: {}", check.name, check.fix.as_deref().unwrap());
+        }
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_check_against_mock_server() {
+        let mock_config = mock::MockConfig {
+            tokens: ["good".to_string()].into(),
+            ..Default::default()
+        };
+        let addr = mock::spawn(mock_config).await;
+
+        let url = format!("http://{}", addr);
+        let status =
+            |checks: &[Check], name| checks.iter().find(|c| c.name == name).unwrap().status;
+        let mut config = Config {
+            token: "good".into(),
+            ..Default::default()
+        };
+        let checks = run(&config, &url).await;
+        for name in ["DNS", "Connect", "ServerInfo", "Auth", "Detect"] {
+            assert_eq!(status(&checks, name), Status::Pass, "{}", render(&checks));
+        }
+        assert_eq!(status(&checks, "TLS"), Status::Skip);
+
+        config.token = "bad".into();
+        let checks = run(&config, &url).await;
+        assert_eq!(status(&checks, "Auth"), Status::Fail);
+        assert_eq!(status(&checks, "Detect"), Status::Skip);
+        assert!(render(&checks).contains("Auth: Check the access token"));
+    }
+}
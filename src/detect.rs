@@ -0,0 +1,836 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::Sender;
+use tokio::sync::{watch, Notify};
+use tokio::task::JoinSet;
+use tokio_stream::StreamExt;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::backend::{DetectionBackend, QuotaExceeded, ResponseStream, Session};
+use crate::md5rs::DetectRequest;
+use crate::window::Window;
+use crate::{Bbox, Config, ExportFrame, WebpItem};
+
+pub(crate) type MediaReceiver = Arc<tokio::sync::Mutex<tokio::sync::mpsc::Receiver<WebpItem>>>;
+
+/// Move media items onto an async channel so detection streams can wait for
+/// them without blocking the runtime.
+pub(crate) fn forward_media(media_q_r: crossbeam_channel::Receiver<WebpItem>) -> MediaReceiver {
+    let (tx, rx) = tokio::sync::mpsc::channel(1);
+    thread::spawn(move || {
+        for item in media_q_r.iter() {
+            if tx.blocking_send(item).is_err() {
+                break;
+            }
+        }
+    });
+    Arc::new(tokio::sync::Mutex::new(rx))
+}
+
+/// Why the run stopped before every file was processed.
+#[derive(Debug, Clone)]
+pub(crate) enum Stop {
+    /// Every stream ran out of reconnect attempts.
+    GaveUp,
+    /// Every stream was dropped, at least one for an exhausted quota.
+    QuotaExceeded(QuotaExceeded),
+    /// A stream panicked.
+    Failed(String),
+}
+
+/// How the detection streams of a run ended.
+pub(crate) struct Outcome {
+    pub stop: Option<Stop>,
+    /// Frames taken from the media queue that were never exported.
+    pub unanswered: usize,
+}
+
+/// An authenticated server to stream frames to.
+pub(crate) struct Endpoint<B> {
+    pub backend: B,
+    pub session: Session,
+    /// Recorded with every frame the server answers.
+    pub model: Option<String>,
+}
+
+/// Stream frames to every endpoint, `config.streams_per_server` streams
+/// each, until the media queue is exhausted and every frame is answered or
+/// the run has to stop.
+///
+/// Streams pull the next frame whenever their in-flight window has room, so
+/// faster servers get more of the work. A stream that fails hands its
+/// unanswered frames over to the others while it reconnects.
+pub(crate) async fn run<B: DetectionBackend + Clone + 'static>(
+    config: Arc<Config>,
+    endpoints: Vec<Endpoint<B>>,
+    media: MediaReceiver,
+    export_q_s: Sender<ExportFrame>,
+) -> Outcome {
+    let streams = config.streams_per_server.max(1);
+    let (stopping, _) = watch::channel(false);
+    let dispatch = Arc::new(Dispatch {
+        media,
+        media_done: AtomicBool::new(false),
+        handover: Mutex::new(VecDeque::new()),
+        outstanding: AtomicUsize::new(0),
+        live_lanes: AtomicUsize::new(endpoints.len() * streams),
+        quota: Mutex::new(None),
+        stop: Mutex::new(None),
+        stopping,
+        wake: Notify::new(),
+        export_q_s,
+    });
+
+    let mut lanes = JoinSet::new();
+    let mut health = Vec::new();
+    for Endpoint {
+        backend,
+        session,
+        model,
+    } in endpoints
+    {
+        let endpoint = Arc::new(EndpointHealth::new(backend.endpoint()));
+        health.push(Arc::clone(&endpoint));
+        for _ in 0..streams {
+            let lane = Lane {
+                backend: backend.clone(),
+                session: session.clone(),
+                model: model.clone(),
+                config: Arc::clone(&config),
+                dispatch: Arc::clone(&dispatch),
+                health: Arc::clone(&endpoint),
+                inflight: Arc::new(Mutex::new(InFlight::new(Window::new(
+                    config.max_in_flight,
+                    config.adaptive_window,
+                    backend.batch_size(),
+                )))),
+                attempt: 0,
+            };
+            lanes.spawn(lane.run());
+        }
+    }
+
+    let mut timed_out = 0;
+    while let Some(report) = lanes.join_next().await {
+        let report = match report {
+            Ok(report) => report,
+            Err(e) => {
+                error!("Detection stream failed: {}", e);
+                dispatch.stop(Stop::Failed(e.to_string()));
+                continue;
+            }
+        };
+        timed_out += report.timed_out;
+        if let Some(srtt) = report.window.srtt() {
+            info!(
+                "{}: in-flight window {} frames, round trip {:?}",
+                report.endpoint,
+                report.window.limit(),
+                srtt
+            );
+        }
+    }
+    for endpoint in &health {
+        info!(
+            "{}: {} frames answered, {} stream errors",
+            endpoint.endpoint,
+            endpoint.answered.load(Ordering::SeqCst),
+            endpoint.errors.load(Ordering::SeqCst)
+        );
+    }
+    if timed_out > 0 {
+        warn!(
+            "{} frames were exported with a timeout error after {} retries",
+            timed_out, config.frame_retries
+        );
+    }
+
+    let stop = dispatch.stop.lock().unwrap().clone();
+    Outcome {
+        stop,
+        unanswered: dispatch.outstanding.load(Ordering::SeqCst),
+    }
+}
+
+/// State shared by every detection stream of a run.
+struct Dispatch {
+    media: MediaReceiver,
+    media_done: AtomicBool,
+    /// Frames left unanswered by a failed stream, for any stream to send.
+    handover: Mutex<VecDeque<PendingFrame>>,
+    /// Frames taken from the media queue and not exported yet.
+    outstanding: AtomicUsize,
+    live_lanes: AtomicUsize,
+    /// Set once a stream is dropped for an exhausted quota.
+    quota: Mutex<Option<QuotaExceeded>>,
+    stop: Mutex<Option<Stop>>,
+    stopping: watch::Sender<bool>,
+    /// Wakes streams waiting for handed over frames or the end of the run.
+    wake: Notify,
+    export_q_s: Sender<ExportFrame>,
+}
+
+impl Dispatch {
+    fn export(&self, frame: ExportFrame) {
+        self.send_to_exporter(frame);
+        if self.outstanding.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.wake.notify_waiters();
+        }
+    }
+
+    /// Stop the run once the exporter has gone away, as nothing more can be
+    /// written.
+    fn send_to_exporter(&self, frame: ExportFrame) {
+        if self.export_q_s.send(frame).is_err() {
+            error!("The exporter stopped, frames can no longer be written");
+            self.stop(Stop::Failed("The exporter stopped".to_string()));
+        }
+    }
+
+    fn hand_over(&self, frames: Vec<PendingFrame>) {
+        self.handover.lock().unwrap().extend(frames);
+        self.wake.notify_waiters();
+    }
+
+    fn stop(&self, reason: Stop) {
+        self.stop.lock().unwrap().get_or_insert(reason);
+        self.stopping.send_replace(true);
+        self.wake.notify_waiters();
+    }
+
+    /// Whether every frame has been taken from the media queue and exported.
+    fn finished(&self) -> bool {
+        self.media_done.load(Ordering::SeqCst) && self.outstanding.load(Ordering::SeqCst) == 0
+    }
+
+    /// Wait until another stream hands frames over or the run is over.
+    /// Returns whether there is more to send.
+    async fn wait_for_work(&self, stopping: &watch::Receiver<bool>) -> bool {
+        loop {
+            let notified = self.wake.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            if *stopping.borrow() || self.finished() {
+                return false;
+            }
+            if !self.media_done.load(Ordering::SeqCst) || !self.handover.lock().unwrap().is_empty()
+            {
+                return true;
+            }
+            notified.await;
+        }
+    }
+}
+
+/// Counters for one server, shared by its streams.
+struct EndpointHealth {
+    endpoint: String,
+    answered: AtomicUsize,
+    errors: AtomicUsize,
+    consecutive_errors: AtomicUsize,
+}
+
+impl EndpointHealth {
+    fn new(endpoint: String) -> Self {
+        Self {
+            endpoint,
+            answered: AtomicUsize::new(0),
+            errors: AtomicUsize::new(0),
+            consecutive_errors: AtomicUsize::new(0),
+        }
+    }
+
+    fn record_answer(&self) {
+        self.answered.fetch_add(1, Ordering::SeqCst);
+        self.consecutive_errors.store(0, Ordering::SeqCst);
+    }
+
+    /// Count a failed stream, returning the number of failures in a row.
+    fn record_error(&self) -> usize {
+        self.errors.fetch_add(1, Ordering::SeqCst);
+        self.consecutive_errors.fetch_add(1, Ordering::SeqCst) + 1
+    }
+}
+
+/// A frame that has been sent to the server and is waiting for its detection.
+struct PendingFrame {
+    export_frame: ExportFrame,
+    request: DetectRequest,
+    /// Generation of the stream that last sent the frame, `None` once it is
+    /// due to be sent again.
+    generation: Option<usize>,
+    sent_at: Instant,
+    /// Times the frame was sent again because it went unanswered.
+    retries: usize,
+}
+
+/// Frames awaiting a response on one stream, shared between its outbound
+/// side and the receiving loop so they can be resent after a reconnect or a
+/// timeout.
+struct InFlight {
+    generation: usize,
+    frames: HashMap<String, PendingFrame>,
+    resend: VecDeque<String>,
+    /// Limit on `frames`; the outbound stream waits while it is reached.
+    window: Window,
+    /// Wakes the outbound stream when frames are queued for resending or the
+    /// window opens.
+    wake: Arc<Notify>,
+    /// Frames given up on after `frame_retries` retries.
+    timed_out: usize,
+}
+
+impl InFlight {
+    fn new(window: Window) -> Self {
+        Self {
+            generation: 0,
+            frames: HashMap::new(),
+            resend: VecDeque::new(),
+            window,
+            wake: Arc::new(Notify::new()),
+            timed_out: 0,
+        }
+    }
+
+    /// Track a frame about to be sent by the outbound stream of
+    /// `generation`, or give it back if that stream has been retired.
+    fn send(&mut self, pending: PendingFrame, generation: usize) -> Option<PendingFrame> {
+        if self.generation != generation {
+            return Some(pending);
+        }
+        self.frames.insert(pending.request.uuid.clone(), pending);
+        None
+    }
+
+    /// Take the frame answered by a response out of the window.
+    fn answer(&mut self, uuid: &str) -> Option<PendingFrame> {
+        let pending = self.frames.remove(uuid)?;
+        // A retried frame's response may belong to any of its sends.
+        if pending.retries == 0 {
+            self.window.on_response(pending.sent_at.elapsed());
+        }
+        self.wake.notify_one();
+        Some(pending)
+    }
+
+    /// Queue frames sent more than `timeout` ago for resending, or take them
+    /// out with a timeout error once they have been retried `max_retries`
+    /// times.
+    fn time_out(&mut self, timeout: Duration, max_retries: usize) -> Vec<ExportFrame> {
+        let now = Instant::now();
+        let expired: Vec<String> = self
+            .frames
+            .iter()
+            .filter(|(_, pending)| now.duration_since(pending.sent_at) >= timeout)
+            .map(|(uuid, _)| uuid.clone())
+            .collect();
+        let mut failed = Vec::new();
+        for uuid in expired {
+            let pending = self.frames.get_mut(&uuid).unwrap();
+            if pending.retries < max_retries {
+                pending.retries += 1;
+                pending.generation = None;
+                self.resend.push_back(uuid);
+            } else {
+                let mut frame = self.frames.remove(&uuid).unwrap().export_frame;
+                frame.error = Some(format!(
+                    "No response from server after {} attempts",
+                    max_retries + 1
+                ));
+                failed.push(frame);
+            }
+        }
+        self.timed_out += failed.len();
+        if !self.resend.is_empty() || !failed.is_empty() {
+            self.wake.notify_one();
+        }
+        failed
+    }
+
+    /// Stop the current outbound stream from sending more frames.
+    fn retire(&mut self) {
+        self.generation += 1;
+        self.wake.notify_one();
+    }
+
+    /// Queue every pending frame for the next outbound stream. Returns the
+    /// number of frames queued.
+    fn requeue(&mut self) -> usize {
+        self.resend = self.frames.keys().cloned().collect();
+        self.resend.len()
+    }
+
+    /// Retire the current outbound stream and take every pending frame.
+    fn take_all(&mut self) -> Vec<PendingFrame> {
+        self.retire();
+        self.resend.clear();
+        self.frames
+            .drain()
+            .map(|(_, mut pending)| {
+                pending.generation = None;
+                pending
+            })
+            .collect()
+    }
+}
+
+/// Why a detection stream ended without an error.
+enum StreamEnd {
+    /// The server answered everything that was sent.
+    Finished,
+    /// The stream was retired so the session token can be refreshed.
+    Rotated,
+    /// The run is stopping.
+    Stopped,
+}
+
+/// What a stream reports when it ends.
+struct LaneReport {
+    endpoint: String,
+    window: Window,
+    timed_out: usize,
+}
+
+/// One detection stream to one endpoint, reopened until the run is over.
+struct Lane<B> {
+    backend: B,
+    session: Session,
+    model: Option<String>,
+    config: Arc<Config>,
+    dispatch: Arc<Dispatch>,
+    health: Arc<EndpointHealth>,
+    inflight: Arc<Mutex<InFlight>>,
+    attempt: usize,
+}
+
+impl<B: DetectionBackend> Lane<B> {
+    async fn run(mut self) -> LaneReport {
+        let mut stopping = self.dispatch.stopping.subscribe();
+        while !*stopping.borrow() {
+            let outbound = detect_stream(
+                &self.inflight,
+                &self.dispatch,
+                self.config.iou,
+                self.config.conf,
+            );
+            let result = match self
+                .backend
+                .detect(&self.session.token, Box::pin(outbound))
+                .await
+            {
+                Ok(inbound) => self.receive_detections(inbound, &mut stopping).await,
+                Err(status) => Err(status),
+            };
+
+            match result {
+                Ok(StreamEnd::Stopped) => break,
+                Ok(StreamEnd::Finished) => {
+                    // The server closed the stream without answering these;
+                    // they will not be answered on it any more.
+                    let failed = self
+                        .inflight
+                        .lock()
+                        .unwrap()
+                        .time_out(Duration::ZERO, self.config.frame_retries);
+                    for frame in failed {
+                        self.dispatch.export(frame);
+                    }
+                    let pending = self.inflight.lock().unwrap().requeue();
+                    if pending > 0 {
+                        warn!("Resending {} frames the server did not answer", pending);
+                        continue;
+                    }
+                    if !self.dispatch.wait_for_work(&stopping).await {
+                        break;
+                    }
+                }
+                Ok(StreamEnd::Rotated) => {
+                    info!("Refreshing session token");
+                    match self.backend.auth(&self.config.token).await {
+                        Ok(refreshed) => self.session = refreshed,
+                        Err(e) => {
+                            if let Some(exceeded) = e.downcast_ref::<QuotaExceeded>() {
+                                self.drop_for_quota(exceeded.clone());
+                                break;
+                            }
+                            error!("Failed to refresh session token: {}", e);
+                            if !self.reconnect(true).await {
+                                break;
+                            }
+                        }
+                    }
+                    let pending = self.inflight.lock().unwrap().requeue();
+                    if pending > 0 {
+                        info!("Resending {} unanswered frames", pending);
+                    }
+                }
+                Err(status) => {
+                    if let Some(exceeded) = QuotaExceeded::from_status(&status) {
+                        self.drop_for_quota(exceeded);
+                        break;
+                    }
+                    let failures = self.health.record_error();
+                    error!(
+                        "Detect stream to {} interrupted ({} in a row): {}",
+                        self.health.endpoint,
+                        failures,
+                        status.message()
+                    );
+                    let pending = self.inflight.lock().unwrap().take_all();
+                    if !pending.is_empty() {
+                        info!("Handing {} in-flight frames over", pending.len());
+                        self.dispatch.hand_over(pending);
+                    }
+                    // An expired session only needs a new token, not a backoff.
+                    let delay = status.code() != tonic::Code::Unauthenticated;
+                    if !self.reconnect(delay).await {
+                        break;
+                    }
+                }
+            }
+        }
+
+        let mut inflight = self.inflight.lock().unwrap();
+        let pending = inflight.take_all();
+        if !pending.is_empty() {
+            self.dispatch.hand_over(pending);
+        }
+        if self.dispatch.live_lanes.fetch_sub(1, Ordering::SeqCst) == 1 && !self.dispatch.finished()
+        {
+            let exceeded = self.dispatch.quota.lock().unwrap().clone();
+            self.dispatch
+                .stop(exceeded.map_or(Stop::GaveUp, Stop::QuotaExceeded));
+        }
+        LaneReport {
+            endpoint: self.health.endpoint.clone(),
+            window: inflight.window.clone(),
+            timed_out: inflight.timed_out,
+        }
+    }
+
+    async fn receive_detections(
+        &mut self,
+        mut inbound: ResponseStream,
+        stopping: &mut watch::Receiver<bool>,
+    ) -> Result<StreamEnd, tonic::Status> {
+        let mut rotating = false;
+        let mut deadlines = tokio::time::interval(
+            (self.config.frame_timeout / 4)
+                .clamp(Duration::from_millis(100), Duration::from_secs(5)),
+        );
+        loop {
+            let refresh_at = self.session.refresh_at.filter(|_| !rotating);
+            let refresh = async {
+                match refresh_at {
+                    Some(refresh_at) => tokio::time::sleep_until(refresh_at.into()).await,
+                    None => std::future::pending().await,
+                }
+            };
+            let next = tokio::select! {
+                next = inbound.next() => next,
+                _ = refresh => {
+                    // Let the server finish what it has, then reopen the
+                    // stream with a fresh token.
+                    self.inflight.lock().unwrap().retire();
+                    rotating = true;
+                    continue;
+                }
+                _ = deadlines.tick() => {
+                    let failed = self
+                        .inflight
+                        .lock()
+                        .unwrap()
+                        .time_out(self.config.frame_timeout, self.config.frame_retries);
+                    for frame in failed {
+                        warn!(
+                            "No response for frame {} of {}",
+                            frame.frame_index,
+                            frame.file.file_path.display()
+                        );
+                        self.dispatch.export(frame);
+                    }
+                    continue;
+                }
+                _ = stopping.wait_for(|stopping| *stopping) => {
+                    self.inflight.lock().unwrap().retire();
+                    return Ok(StreamEnd::Stopped);
+                }
+            };
+            let Some(response) = next else {
+                break;
+            };
+            let response = response?;
+            self.attempt = 0;
+            let pending = self.inflight.lock().unwrap().answer(&response.uuid);
+            if let Some(PendingFrame {
+                export_frame: mut frame,
+                ..
+            }) = pending
+            {
+                frame.bboxes = Some(
+                    response
+                        .bboxs
+                        .into_iter()
+                        .map(|bbox| Bbox {
+                            x1: bbox.x1,
+                            y1: bbox.y1,
+                            x2: bbox.x2,
+                            y2: bbox.y2,
+                            class: bbox.class as usize,
+                            score: bbox.score,
+                        })
+                        .collect(),
+                );
+                frame.label = Some(response.label);
+                frame.model = self.model.clone();
+                self.health.record_answer();
+                self.dispatch.export(frame);
+            }
+        }
+        Ok(if rotating {
+            StreamEnd::Rotated
+        } else {
+            StreamEnd::Finished
+        })
+    }
+
+    /// Give up on this stream, leaving its frames to the others. The run only
+    /// stops once every stream is gone.
+    fn drop_for_quota(&self, exceeded: QuotaExceeded) {
+        warn!(
+            "Daily quota exceeded on {}, dropping the stream",
+            self.health.endpoint
+        );
+        self.dispatch.quota.lock().unwrap().get_or_insert(exceeded);
+    }
+
+    /// Wait with exponential backoff and authenticate again until a new
+    /// session is obtained. Returns false once `max_retries` is exhausted or
+    /// the quota runs out. Without `delay` the first attempt is made
+    /// immediately.
+    async fn reconnect(&mut self, mut delay: bool) -> bool {
+        while self.attempt < self.config.max_retries {
+            self.attempt += 1;
+            if delay {
+                let backoff = retry_backoff(self.attempt);
+                warn!(
+                    "Reconnecting to {} in {:?} (attempt {} of {})",
+                    self.health.endpoint, backoff, self.attempt, self.config.max_retries
+                );
+                tokio::time::sleep(backoff).await;
+            }
+            delay = true;
+            match self.backend.auth(&self.config.token).await {
+                Ok(refreshed) => {
+                    self.session = refreshed;
+                    return true;
+                }
+                Err(e) => match e.downcast::<QuotaExceeded>() {
+                    Ok(exceeded) => {
+                        self.drop_for_quota(exceeded);
+                        return false;
+                    }
+                    Err(e) => error!("Re-authentication failed: {}", e),
+                },
+            }
+        }
+        error!(
+            "Giving up on {} after {} reconnect attempts",
+            self.health.endpoint, self.attempt
+        );
+        false
+    }
+}
+
+fn detect_stream(
+    inflight: &Arc<Mutex<InFlight>>,
+    dispatch: &Arc<Dispatch>,
+    iou: f32,
+    conf: f32,
+) -> impl tokio_stream::Stream<Item = DetectRequest> + Send + 'static {
+    let inflight = Arc::clone(inflight);
+    let dispatch = Arc::clone(dispatch);
+    let (generation, wake) = {
+        let inflight = inflight.lock().unwrap();
+        (inflight.generation, Arc::clone(&inflight.wake))
+    };
+    async_stream::stream! {
+        loop {
+            let (resend, full) = {
+                let mut inflight = inflight.lock().unwrap();
+                if inflight.generation != generation {
+                    break;
+                }
+                let mut resend = None;
+                while let Some(uuid) = inflight.resend.pop_front() {
+                    if let Some(pending) = inflight.frames.get_mut(&uuid) {
+                        if pending.generation != Some(generation) {
+                            pending.generation = Some(generation);
+                            pending.sent_at = Instant::now();
+                            resend = Some(pending.request.clone());
+                            break;
+                        }
+                    }
+                }
+                (resend, inflight.frames.len() >= inflight.window.limit())
+            };
+            if let Some(request) = resend {
+                yield request;
+                continue;
+            }
+            if full {
+                wake.notified().await;
+                continue;
+            }
+            // Frames handed over by a failed stream go before new ones.
+            let handed_over = dispatch.handover.lock().unwrap().pop_front();
+            if let Some(mut pending) = handed_over {
+                let request = pending.request.clone();
+                pending.generation = Some(generation);
+                pending.sent_at = Instant::now();
+                let retired = inflight.lock().unwrap().send(pending, generation);
+                if let Some(pending) = retired {
+                    dispatch.hand_over(vec![pending]);
+                    break;
+                }
+                yield request;
+                continue;
+            }
+            let next = tokio::select! {
+                next = async { dispatch.media.lock().await.recv().await } => next,
+                // Frames were queued for resending or handed over meanwhile.
+                _ = wake.notified() => continue,
+                _ = dispatch.wake.notified() => continue,
+            };
+            let item = match next {
+                Some(item) => item,
+                None => {
+                    if !dispatch.media_done.swap(true, Ordering::SeqCst) {
+                        dispatch.wake.notify_waiters();
+                    }
+                    // A retired stream may have handed over a frame meanwhile.
+                    if !inflight.lock().unwrap().resend.is_empty()
+                        || !dispatch.handover.lock().unwrap().is_empty()
+                    {
+                        continue;
+                    }
+                    break;
+                }
+            };
+            match item {
+                WebpItem::Frame(frame) => {
+                    dispatch.outstanding.fetch_add(1, Ordering::SeqCst);
+                    let uuid = Uuid::new_v4().to_string();
+                    let export_frame = ExportFrame {
+                        file: frame.file.clone(),
+                        frame_index: frame.frame_index,
+                        shoot_time: frame.shoot_time.map(|t| t.to_string()),
+                        total_frames: frame.total_frames,
+                        bboxes: None,
+                        label: None,
+                        error: None,
+                        model: None,
+                    };
+                    let score = frame.file.settings.conf.unwrap_or(conf);
+                    let request = DetectRequest {
+                        uuid,
+                        image: frame.image,
+                        width: frame.width as i32,
+                        height: frame.height as i32,
+                        iou,
+                        score,
+                        encoding: frame.encoding.name().to_string(),
+                    };
+                    let pending = PendingFrame {
+                        export_frame,
+                        request: request.clone(),
+                        generation: Some(generation),
+                        sent_at: Instant::now(),
+                        retries: 0,
+                    };
+                    let retired = inflight.lock().unwrap().send(pending, generation);
+                    // The connection this stream belongs to is gone; let
+                    // another stream send the frame.
+                    if let Some(pending) = retired {
+                        dispatch.hand_over(vec![pending]);
+                        break;
+                    }
+                    yield request;
+                }
+                WebpItem::ErrFile(file) => {
+                    dispatch.send_to_exporter(ExportFrame {
+                        file: file.file.clone(),
+                        frame_index: 0,
+                        shoot_time: None,
+                        total_frames: 0,
+                        bboxes: None,
+                        label: None,
+                        error: Some(file.error.to_string()),
+                        model: None,
+                    });
+                }
+            }
+        }
+    }
+}
+
+fn retry_backoff(attempt: usize) -> Duration {
+    let secs = 1u64 << attempt.saturating_sub(1).min(6);
+    Duration::from_secs(secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::media::{Encoding, Frame};
+    use crate::FileItem;
+
+    #[tokio::test]
+    async fn test_outbound_waits_for_window() {
+        let (media_s, media_r) = tokio::sync::mpsc::channel(4);
+        for i in 0..4 {
+            let frame = Frame {
+                file: FileItem::new(0, i, PathBuf::from(format!("IMG_{}.jpg", i)), None),
+                image: vec![i as u8],
+                encoding: Encoding::Webp,
+                width: 1,
+                height: 1,
+                frame_index: 0,
+                total_frames: 1,
+                shoot_time: None,
+            };
+            media_s.send(WebpItem::Frame(frame)).await.unwrap();
+        }
+        let (export_q_s, _export_q_r) = crossbeam_channel::unbounded();
+        let dispatch = Arc::new(Dispatch {
+            media: Arc::new(tokio::sync::Mutex::new(media_r)),
+            media_done: AtomicBool::new(false),
+            handover: Mutex::new(VecDeque::new()),
+            outstanding: AtomicUsize::new(0),
+            live_lanes: AtomicUsize::new(1),
+            quota: Mutex::new(None),
+            stop: Mutex::new(None),
+            stopping: watch::channel(false).0,
+            wake: Notify::new(),
+            export_q_s,
+        });
+        let inflight = Arc::new(Mutex::new(InFlight::new(Window::new(2, false, 1))));
+        let outbound = detect_stream(&inflight, &dispatch, 0.45, 0.2);
+        tokio::pin!(outbound);
+
+        let first = outbound.next().await.unwrap();
+        outbound.next().await.unwrap();
+        let wait = Duration::from_millis(100);
+        assert!(tokio::time::timeout(wait, outbound.next()).await.is_err());
+        inflight.lock().unwrap().answer(&first.uuid);
+        let third = tokio::time::timeout(wait, outbound.next()).await.unwrap();
+        assert!(third.is_some());
+    }
+}
@@ -8,7 +8,7 @@ use csv::WriterBuilder;
 use serde::{Deserialize, Serialize};
 use tracing::info;
 
-use crate::utils::FileItem;
+use crate::utils::{Container, FileItem};
 use crate::ExportFormat;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -31,6 +31,9 @@ pub struct ExportFrame {
     pub bboxes: Option<Vec<Bbox>>,
     pub label: Option<Vec<String>>,
     pub error: Option<String>,
+    /// Model and server version that answered the frame.
+    #[serde(default)]
+    pub model: Option<String>,
 }
 
 pub fn parse_export_csv<P: AsRef<Path>>(csv: P) -> Result<Vec<ExportFrame>> {
@@ -39,13 +42,22 @@ pub fn parse_export_csv<P: AsRef<Path>>(csv: P) -> Result<Vec<ExportFrame>> {
     let mut export_data = Vec::new();
     for frame in rdr.records() {
         let frame = frame?;
+        // Older results hold the absolute path in `file_path`.
+        let file_path: PathBuf = frame
+            .get(10)
+            .filter(|p| !p.is_empty())
+            .unwrap_or(&frame[2])
+            .into();
         let file_item = FileItem {
             folder_id: frame[0].parse::<_>()?,
             file_id: frame[1].parse::<_>()?,
-            file_path: frame[2].parse()?,
-            tmp_path: frame[2].parse()?,
+            relative_path: frame[2].to_string(),
+            file_path: file_path.clone(),
+            tmp_path: file_path,
+            container: frame.get(11).and_then(Container::from_name),
+            settings: Default::default(),
         };
-        let bboxes = frame[7].to_string().replace("\"\"", "\"");
+        let bboxes = frame[6].replace("\"\"", "\"");
         let bboxes = serde_json::from_str(&bboxes)?;
         let frame_item = ExportFrame {
             file: file_item,
@@ -53,58 +65,95 @@ pub fn parse_export_csv<P: AsRef<Path>>(csv: P) -> Result<Vec<ExportFrame>> {
             frame_index: frame[4].parse::<_>()?,
             total_frames: frame[5].parse::<_>()?,
             bboxes,
-            label: Some(
-                frame[8]
-                    .to_string()
-                    .split(";")
-                    .map(|s| s.to_string())
-                    .collect(),
-            ),
-            error: Some(frame[9].to_string()),
+            label: Some(frame[7].split(';').map(|s| s.to_string()).collect()),
+            error: Some(frame[8].to_string()).filter(|e| !e.is_empty()),
+            model: frame.get(9).filter(|m| !m.is_empty()).map(str::to_string),
         };
         export_data.push(frame_item);
     }
     Ok(export_data)
 }
 
+/// Parse a JSON result file. Paths of results exported without
+/// `absolute_path` are the relative ones until resolved against the folder.
+pub fn parse_export_json<P: AsRef<Path>>(json: P) -> Result<Vec<ExportFrame>> {
+    let json = std::fs::read_to_string(json)?;
+    let mut frames: Vec<ExportFrame> = serde_json::from_str(&json)?;
+    for f in frames.iter_mut() {
+        if f.file.file_path.as_os_str().is_empty() {
+            f.file.file_path = PathBuf::from(&f.file.relative_path);
+        }
+        f.file.tmp_path = f.file.file_path.clone();
+    }
+    Ok(frames)
+}
+
+/// Where results go and which paths they include.
+#[derive(Debug, Clone, Copy)]
+pub struct ExportTarget<'a> {
+    pub folder: &'a Path,
+    pub format: ExportFormat,
+    /// Also write the absolute path of every file.
+    pub absolute_paths: bool,
+}
+
 pub fn export_worker(
     checkpoint: usize,
     checkpoint_counter: &Arc<Mutex<usize>>,
-    format: &ExportFormat,
-    folder_path: &PathBuf,
+    target: ExportTarget,
     export_q_r: crossbeam_channel::Receiver<ExportFrame>,
     export_data: &Arc<Mutex<Vec<ExportFrame>>>,
 ) {
-    loop {
-        match export_q_r.recv() {
-            Ok(export_frame) => {
-                let mut checkpoint_counter = checkpoint_counter.lock().unwrap();
-                if *checkpoint_counter % checkpoint == 0 && *checkpoint_counter != 0 {
-                    let export_data = export_data.lock().unwrap();
-                    info!("Exported {} frames", export_data.len());
-                    match format {
-                        ExportFormat::Json => write_json(&export_data, folder_path).unwrap(),
-                        ExportFormat::Csv => write_csv(&export_data, folder_path).unwrap(),
-                    }
-                }
-                export_data.lock().unwrap().push(export_frame);
-                *checkpoint_counter += 1;
-            }
-            Err(_) => break,
+    while let Ok(export_frame) = export_q_r.recv() {
+        let mut checkpoint_counter = checkpoint_counter.lock().unwrap();
+        if *checkpoint_counter % checkpoint == 0 && *checkpoint_counter != 0 {
+            let export_data = export_data.lock().unwrap();
+            info!("Exported {} frames", export_data.len());
+            write(&export_data, target).unwrap();
         }
+        export_data.lock().unwrap().push(export_frame);
+        *checkpoint_counter += 1;
+    }
+}
+
+/// Where results and checkpoints for `folder_path` are written.
+pub fn result_path(folder_path: &Path, export_format: &ExportFormat) -> PathBuf {
+    match export_format {
+        ExportFormat::Json => folder_path.join("result.json"),
+        ExportFormat::Csv => folder_path.join("result.csv"),
+    }
+}
+
+fn write(export_data: &[ExportFrame], target: ExportTarget) -> Result<()> {
+    match target.format {
+        ExportFormat::Json => write_json(export_data, target.folder, target.absolute_paths),
+        ExportFormat::Csv => write_csv(export_data, target.folder, target.absolute_paths),
     }
 }
 
-fn write_json(export_data: &Vec<ExportFrame>, folder_path: &PathBuf) -> Result<()> {
-    let json = serde_json::to_string_pretty(export_data)?;
-    let json_path = folder_path.join("result.json");
+fn write_json(export_data: &[ExportFrame], folder_path: &Path, absolute_paths: bool) -> Result<()> {
+    let json = if absolute_paths {
+        serde_json::to_string_pretty(export_data)?
+    } else {
+        // An empty absolute path is left out.
+        let relative: Vec<ExportFrame> = export_data
+            .iter()
+            .map(|f| {
+                let mut f = f.clone();
+                f.file.file_path = PathBuf::new();
+                f
+            })
+            .collect();
+        serde_json::to_string_pretty(&relative)?
+    };
+    let json_path = result_path(folder_path, &ExportFormat::Json);
     let mut file = File::create(json_path)?;
     file.write_all(json.as_bytes())?;
     Ok(())
 }
 
-fn write_csv(export_data: &Vec<ExportFrame>, folder_path: &PathBuf) -> Result<()> {
-    let csv_path = folder_path.join("result.csv");
+fn write_csv(export_data: &[ExportFrame], folder_path: &Path, absolute_paths: bool) -> Result<()> {
+    let csv_path = result_path(folder_path, &ExportFormat::Csv);
     let mut wtr = WriterBuilder::new()
         .has_headers(false)
         .from_path(csv_path)?;
@@ -118,17 +167,15 @@ fn write_csv(export_data: &Vec<ExportFrame>, folder_path: &PathBuf) -> Result<()
         "bboxes",
         "label",
         "error",
+        "model",
+        "absolute_path",
+        "container",
     ])?;
     for export_frame in export_data {
-        wtr.write_record(&[
+        wtr.write_record([
             export_frame.file.folder_id.to_string().as_str(),
             export_frame.file.file_id.to_string().as_str(),
-            export_frame
-                .file
-                .file_path
-                .to_string_lossy()
-                .into_owned()
-                .as_str(),
+            export_frame.file.relative_path.as_str(),
             export_frame
                 .shoot_time
                 .clone()
@@ -148,28 +195,27 @@ fn write_csv(export_data: &Vec<ExportFrame>, folder_path: &PathBuf) -> Result<()
                 .clone()
                 .unwrap_or("".to_string())
                 .as_str(),
+            export_frame.model.as_deref().unwrap_or_default(),
+            match absolute_paths {
+                true => export_frame.file.file_path.to_string_lossy(),
+                false => "".into(),
+            }
+            .as_ref(),
+            export_frame
+                .file
+                .container
+                .map(|c| c.name())
+                .unwrap_or_default(),
         ])?;
     }
     wtr.flush()?;
     Ok(())
 }
 
-pub fn export(
-    folder_path: &PathBuf,
-    export_data: Arc<Mutex<Vec<ExportFrame>>>,
-    export_format: &ExportFormat,
-) -> Result<()> {
+pub fn export(export_data: Arc<Mutex<Vec<ExportFrame>>>, target: ExportTarget) -> Result<()> {
     let export_data = export_data.lock().unwrap();
     info!("Exported {} frames", export_data.len());
-    match export_format {
-        ExportFormat::Json => {
-            write_json(&export_data, folder_path)?;
-        }
-        ExportFormat::Csv => {
-            write_csv(&export_data, folder_path)?;
-        }
-    }
-    Ok(())
+    write(&export_data, target)
 }
 
 #[cfg(test)]
@@ -181,5 +227,10 @@ mod tests {
         // let csv = Path::new("input/result.csv");
         let export_data = parse_export_csv("input/result.csv").unwrap();
         assert_eq!(export_data.len(), 11);
+        // Written before relative paths, file_path is absolute.
+        let first = &export_data[0].file;
+        assert_eq!(first.relative_path, "/data/survey/cam01/IMG_0001.JPG");
+        assert_eq!(first.file_path, Path::new(&first.relative_path));
+        assert!(export_data[0].error.is_none());
     }
 }
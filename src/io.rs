@@ -1,17 +1,21 @@
+use std::ffi::OsString;
 use std::fs;
 use std::path::{Path, PathBuf};
 
-use anyhow::{Ok, Result};
+use anyhow::Result;
 use crossbeam_channel::Sender;
+use tracing::warn;
 use uuid::Uuid;
 
 use crate::utils::FileItem;
 
 fn copy_to_buff(file_path: &PathBuf, buff_path: &Path) -> Result<PathBuf> {
-    let mut tmp_name = Uuid::new_v4().to_string();
-    let ext = file_path.extension().unwrap();
-    tmp_name.push_str(".");
-    tmp_name.push_str(ext.to_str().unwrap());
+    // Keep the extension, whatever its encoding, as the decoders go by it.
+    let mut tmp_name = OsString::from(Uuid::new_v4().to_string());
+    if let Some(ext) = file_path.extension() {
+        tmp_name.push(".");
+        tmp_name.push(ext);
+    }
     let temp_path = buff_path.join(tmp_name);
     fs::copy(file_path, &temp_path)?;
     Ok(temp_path)
@@ -19,7 +23,15 @@ fn copy_to_buff(file_path: &PathBuf, buff_path: &Path) -> Result<PathBuf> {
 
 pub fn io_worker(buff_path: &Path, file: &FileItem, io_q_s: Sender<FileItem>) -> Result<()> {
     let mut new_file = file.clone();
-    new_file.tmp_path = copy_to_buff(&file.file_path, buff_path)?;
+    match copy_to_buff(&file.file_path, buff_path) {
+        Ok(tmp_path) => new_file.tmp_path = tmp_path,
+        // Decode straight from the file, reporting any error from there.
+        Err(e) => warn!(
+            "Failed to copy {} to the buffer: {:?}",
+            file.file_path.display(),
+            e
+        ),
+    }
     io_q_s.send(new_file)?;
     Ok(())
 }
@@ -1,42 +1,65 @@
 use std::collections::{HashMap, HashSet};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use crossbeam_channel::{bounded, unbounded};
 use rayon::prelude::*;
-use tonic::{
-    transport::{Certificate, Channel, ClientTlsConfig},
-    Request,
-};
+use thiserror::Error;
 use tracing::{error, info};
-use url::Url;
-use uuid::Uuid;
 
-use md5rs::md5rs_client::Md5rsClient;
-use md5rs::{AuthRequest, DetectRequest};
+use detect::{forward_media, Endpoint, Stop};
 
 pub mod md5rs {
     tonic::include_proto!("md5rs");
 }
 
+pub mod backend;
+pub mod check;
+mod detect;
 pub mod export;
 pub mod io;
 pub mod log;
 pub mod media;
+pub mod mock;
+pub mod profile;
+pub mod proxy;
+pub mod tls;
+pub mod token;
 pub mod utils;
+pub mod window;
 
-pub use export::{export_worker, parse_export_csv, Bbox, ExportFrame};
+pub use backend::{Compression, DetectionBackend, FakeBackend, GrpcBackend, QuotaExceeded};
+pub use export::{
+    export_worker, parse_export_csv, parse_export_json, Bbox, ExportFrame, ExportTarget,
+};
 pub use media::{media_worker, WebpItem};
-pub use utils::FileItem;
+pub use tls::TlsRoots;
+pub use token::Token;
+pub use utils::{Container, Discovery, FileItem, IdMap, IdScheme};
+pub use window::Window;
 
 #[derive(Debug, Clone)]
 pub struct Config {
-    pub folder: String,
-    pub url: String,
-    pub token: String,
+    /// Root folders searched for photos and videos.
+    pub folders: Vec<PathBuf>,
+    /// Files processed besides those found under `folders`.
+    pub files: Vec<PathBuf>,
+    /// Folder the results are written to, by default the only root folder.
+    pub output: Option<PathBuf>,
+    /// Which files under `folders` and in `files` are processed.
+    pub discovery: Discovery,
+    /// How new files and folders get their ids.
+    pub ids: IdScheme,
+    /// JSON file keeping the ids of files and folders across runs.
+    pub id_map: Option<String>,
+    /// Write the absolute path of every file next to its relative path.
+    pub absolute_paths: bool,
+    pub urls: Vec<String>,
+    pub token: Token,
     pub max_frames: Option<usize>,
     pub iframe_only: bool,
     pub iou: f32,
@@ -47,30 +70,172 @@ pub struct Config {
     pub resume_from: Option<String>,
     pub buffer_path: Option<String>,
     pub buffer_size: usize,
+    pub max_retries: usize,
+    /// How long to wait for the detection of a frame before sending it again.
+    pub frame_timeout: Duration,
+    /// How many times an unanswered frame is sent again before it is exported
+    /// with a timeout error.
+    pub frame_retries: usize,
+    /// Most frames awaiting a detection at once.
+    pub max_in_flight: usize,
+    /// Tune the in-flight window below `max_in_flight` from round-trip times.
+    pub adaptive_window: bool,
+    /// Detection streams opened to each server.
+    pub streams_per_server: usize,
+    pub tls_roots: TlsRoots,
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+    pub pins: Vec<String>,
+    /// Proxy URL to tunnel through, overriding `HTTPS_PROXY` and friends.
+    pub proxy: Option<String>,
+    /// Compress gRPC messages in both directions.
+    pub compression: Option<Compression>,
+    /// Interval between HTTP/2 keepalive pings.
+    pub keepalive: Option<Duration>,
+    /// Give up connecting to a server after this long.
+    pub connect_timeout: Option<Duration>,
+    /// Initial HTTP/2 flow control window of each stream, in bytes.
+    pub stream_window: Option<u32>,
+    /// Initial HTTP/2 flow control window of the connection, in bytes.
+    pub connection_window: Option<u32>,
+    /// Most frames per `DetectBatch` message, 1 to send every frame on its own.
+    pub batch_size: usize,
+    /// Longest a frame waits for its batch to fill up.
+    pub batch_latency: Duration,
+}
+
+/// The command line defaults, for callers that only need to talk to a server.
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            folders: Vec::new(),
+            files: Vec::new(),
+            output: None,
+            discovery: Discovery::default(),
+            ids: IdScheme::Sequential,
+            id_map: None,
+            absolute_paths: false,
+            urls: vec!["https://md5rs.hinature.cn".to_string()],
+            token: Token::default(),
+            max_frames: Some(3),
+            iframe_only: true,
+            iou: 0.45,
+            conf: 0.2,
+            quality: 70.0,
+            export: ExportFormat::Json,
+            checkpoint: 100,
+            resume_from: None,
+            buffer_path: None,
+            buffer_size: 20,
+            max_retries: 5,
+            frame_timeout: Duration::from_secs(60),
+            frame_retries: 2,
+            max_in_flight: 64,
+            adaptive_window: true,
+            streams_per_server: 1,
+            tls_roots: TlsRoots::System,
+            client_cert: None,
+            client_key: None,
+            pins: Vec::new(),
+            proxy: None,
+            compression: None,
+            keepalive: None,
+            connect_timeout: None,
+            stream_window: None,
+            connection_window: None,
+            batch_size: 1,
+            batch_latency: Duration::from_millis(50),
+        }
+    }
+}
+
+impl Config {
+    /// Folder `result.json` or `result.csv` is written to.
+    pub fn output_folder(&self) -> Result<PathBuf, ProcessError> {
+        match (&self.output, self.folders.as_slice()) {
+            (Some(output), _) => Ok(output.clone()),
+            (None, [folder]) if self.files.is_empty() => Ok(folder.clone()),
+            _ => Err(ProcessError::OutputRequired),
+        }
+    }
+
+    fn export_target<'a>(&self, folder: &'a Path) -> ExportTarget<'a> {
+        ExportTarget {
+            folder,
+            format: self.export,
+            absolute_paths: self.absolute_paths,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ProcessError {
+    #[error("Pass --output to choose where results are written when processing several folders or a file list")]
+    OutputRequired,
+
+    #[error("Daily quota exceeded with {remaining} files left to process{}. Resume with --resume-from {}", reset_hint(.resets_in), .checkpoint.display())]
+    QuotaExceeded {
+        remaining: usize,
+        resets_in: Option<Duration>,
+        checkpoint: PathBuf,
+    },
+
+    #[error("{file_path} in the checkpoint matches several files, resume with the folders it was written for")]
+    AmbiguousCheckpoint { file_path: String },
+}
+
+fn reset_hint(resets_in: &Option<Duration>) -> String {
+    let Some(resets_in) = resets_in else {
+        return String::new();
+    };
+    let resets_in = chrono::Duration::from_std(*resets_in).unwrap_or(chrono::Duration::zero());
+    format!(
+        ", the quota resets at {}",
+        (chrono::Local::now() + resets_in).format("%Y-%m-%d %H:%M")
+    )
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum ExportFormat {
     Json,
     Csv,
 }
 
-pub async fn process(config: Config, progress_sender: crossbeam_channel::Sender<usize>) -> Result<()> {
-    let url = Url::parse(&config.url)?;
-    let host = url.host_str().unwrap();
-
-    let pem = utils::get_tls_certificate(&config.url)?;
-    let ca = Certificate::from_pem(pem);
-    let tls = ClientTlsConfig::new().ca_certificate(ca).domain_name(host);
-
-    let channel = Channel::from_shared(url.to_string())?
-        .tls_config(tls)?
-        .connect()
-        .await?;
+pub async fn process(
+    config: Config,
+    progress_sender: crossbeam_channel::Sender<usize>,
+) -> Result<()> {
+    let mut backends = Vec::new();
+    for url in &config.urls {
+        match GrpcBackend::connect(&config, url).await {
+            Ok(backend) => backends.push(backend),
+            Err(e) if config.urls.len() == 1 => return Err(e),
+            Err(e) => error!("Failed to connect to {}: {}", url, e),
+        }
+    }
+    if backends.is_empty() {
+        return Err(anyhow::anyhow!("Failed to connect to any server"));
+    }
+    process_with_backends(config, backends, progress_sender).await
+}
 
-    let mut client = Md5rsClient::new(channel);
-    let session_token = auth(&mut client, &config.token).await?;
+/// Run the pipeline against any [`DetectionBackend`].
+pub async fn process_with_backend<B: DetectionBackend + Clone + 'static>(
+    config: Config,
+    backend: B,
+    progress_sender: crossbeam_channel::Sender<usize>,
+) -> Result<()> {
+    process_with_backends(config, vec![backend], progress_sender).await
+}
 
+/// Run the pipeline spreading frames over several backends, e.g. one per
+/// server.
+pub async fn process_with_backends<B: DetectionBackend + Clone + 'static>(
+    config: Config,
+    backends: Vec<B>,
+    progress_sender: crossbeam_channel::Sender<usize>,
+) -> Result<()> {
     cleanup_buffer(&config.buffer_path)?;
 
     if config.checkpoint == 0 {
@@ -78,46 +243,147 @@ pub async fn process(config: Config, progress_sender: crossbeam_channel::Sender<
         return Ok(());
     }
 
-    let folder_path = std::path::PathBuf::from(&config.folder);
-    let folder_path = std::fs::canonicalize(folder_path)?;
+    let output_path = config.output_folder()?;
+    std::fs::create_dir_all(&output_path)?;
+    let output_path = std::fs::canonicalize(output_path)?;
+    let folders = config
+        .folders
+        .iter()
+        .map(|f| {
+            std::fs::canonicalize(f).with_context(|| format!("Folder {} not found", f.display()))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let files = config
+        .files
+        .iter()
+        .map(|f| {
+            std::fs::canonicalize(f)
+                .with_context(|| format!("Listed file {} not found", f.display()))
+        })
+        .collect::<Result<Vec<_>>>()?;
 
-    let imgsz = 1280;
     let start = Instant::now();
 
-    let mut file_paths = utils::index_files_and_folders(&folder_path);
+    let mut id_map = match &config.id_map {
+        Some(path) => IdMap::load(Path::new(path))?,
+        None => IdMap::default(),
+    };
+    let mut file_paths =
+        utils::index_inputs(&folders, &files, &config.discovery, config.ids, &mut id_map)?;
+    if let Some(path) = &config.id_map {
+        id_map.save(Path::new(path))?;
+    }
 
     let export_data = Arc::new(Mutex::new(Vec::new()));
-    let frames = Arc::new(Mutex::new(HashMap::<String, ExportFrame>::new()));
 
-    let file_paths = match config.resume_from {
+    let file_paths = match &config.resume_from {
         Some(checkpoint_path) => {
-            let all_files =
-                resume_from_checkpoint(&checkpoint_path, &mut file_paths, &export_data)?;
+            let all_files = resume_from_checkpoint(checkpoint_path, &mut file_paths, &export_data)?;
             all_files.to_owned()
         }
         None => file_paths,
     };
+    let queued: Vec<String> = file_paths.iter().map(|f| f.relative_path.clone()).collect();
+
+    let single = backends.len() == 1;
+    let mut quota = None;
+    let mut endpoints = Vec::new();
+    let mut servers = Vec::new();
+    for mut backend in backends {
+        let info = match backend.server_info().await {
+            Ok(info) => info,
+            Err(e) if single => return Err(e),
+            Err(e) => {
+                error!("Skipping {}: {}", backend.endpoint(), e);
+                continue;
+            }
+        };
+        match &info {
+            Some(info) => info!(
+                "{} runs {}, labels {:?}",
+                backend.endpoint(),
+                backend::model_description(info),
+                info.labels
+            ),
+            None => info!("{} does not report its model", backend.endpoint()),
+        }
+        match backend.auth(&config.token).await {
+            Ok(session) => {
+                servers.push((backend.endpoint(), info.clone()));
+                endpoints.push(Endpoint {
+                    backend,
+                    session,
+                    model: info.as_ref().map(backend::model_description),
+                });
+            }
+            Err(e) => match e.downcast::<QuotaExceeded>() {
+                Ok(exceeded) => {
+                    error!("Skipping {}: {}", backend.endpoint(), exceeded);
+                    quota.get_or_insert(exceeded);
+                }
+                Err(e) if single => return Err(e),
+                Err(e) => error!("Skipping {}: {}", backend.endpoint(), e),
+            },
+        }
+    }
+    if endpoints.is_empty() {
+        if let Some(exceeded) = quota {
+            // Leave a checkpoint behind so the next run can resume from it.
+            export::export(export_data, config.export_target(&output_path))?;
+            return Err(quota_error(exceeded, &queued, &[], &output_path, &config));
+        }
+        return Err(anyhow::anyhow!("No server accepted the access token"));
+    }
+    let frame_format = backend::negotiate(&servers, config.iou, config.conf)?;
+    for file in &file_paths {
+        if let Some(conf) = file.settings.conf {
+            backend::negotiate(&servers, config.iou, conf).with_context(|| {
+                format!(
+                    "Invalid conf in the {} applying to {}",
+                    utils::FOLDER_SETTINGS_FILE,
+                    file.file_path.display()
+                )
+            })?;
+        }
+    }
+    info!(
+        "Sending {} frames of up to {} pixels",
+        frame_format.encoding.name(),
+        frame_format.imgsz
+    );
 
     let (media_q_s, media_q_r) = bounded(8);
     let (io_q_s, io_q_r) = bounded(config.buffer_size);
     let (export_q_s, export_q_r) = unbounded();
-    let checkpoint_counter = Arc::new(Mutex::new(0 as usize));
+    let checkpoint_counter = Arc::new(Mutex::new(0_usize));
 
-    let buffer_path = config.buffer_path.clone();
-    let folder_path_clone = folder_path.clone();
+    let buffer_path = match &config.buffer_path {
+        Some(path) => {
+            std::fs::create_dir_all(path)
+                .with_context(|| format!("Failed to create buffer folder {}", path))?;
+            Some(std::fs::canonicalize(path)?)
+        }
+        None => None,
+    };
+    let output_path_clone = output_path.clone();
     let export_data_clone = Arc::clone(&export_data);
     let finish = Arc::new(Mutex::new(false));
     let finish_clone = Arc::clone(&finish);
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let cancelled_clone = Arc::clone(&cancelled);
 
     thread::spawn(move || {
         let export_data = Arc::clone(&export_data);
-        let folder_path = folder_path.clone();
+        let output_path = output_path.clone();
         let checkpoint_counter = Arc::clone(&checkpoint_counter);
         export_worker(
             config.checkpoint,
             &checkpoint_counter,
-            &config.export,
-            &folder_path,
+            ExportTarget {
+                folder: &output_path,
+                format: config.export,
+                absolute_paths: config.absolute_paths,
+            },
             export_q_r,
             &export_data,
         );
@@ -127,157 +393,176 @@ pub async fn process(config: Config, progress_sender: crossbeam_channel::Sender<
 
     if let Some(buffer_path) = buffer_path {
         rayon::spawn(move || {
-            std::fs::create_dir_all(&buffer_path).unwrap();
-            let buffer_path = std::fs::canonicalize(buffer_path).unwrap();
-
+            let cancelled_io = Arc::clone(&cancelled);
             let io_handle = thread::spawn(move || {
                 for file in file_paths.iter() {
-                    io::io_worker(&buffer_path, file, io_q_s.clone()).unwrap();
+                    if cancelled_io.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    if let Err(e) = io::io_worker(&buffer_path, file, io_q_s.clone()) {
+                        error!("Stopped buffering files: {:?}", e);
+                        break;
+                    }
                 }
                 drop(io_q_s);
             });
 
             io_q_r.iter().par_bridge().for_each(|file| {
-                media_worker(
+                if cancelled.load(Ordering::SeqCst) {
+                    return;
+                }
+                if let Err(e) = media_worker(
                     file,
-                    imgsz,
+                    frame_format,
                     config.quality,
                     config.iframe_only,
                     config.max_frames,
                     media_q_s.clone(),
                     progress_sender.clone(),
-                );
+                ) {
+                    error!("{:?}", e);
+                }
             });
             io_handle.join().unwrap();
         });
     } else {
         rayon::spawn(move || {
             file_paths.par_iter().for_each(|file| {
-                media_worker(
+                if cancelled.load(Ordering::SeqCst) {
+                    return;
+                }
+                if let Err(e) = media_worker(
                     file.clone(),
-                    imgsz,
+                    frame_format,
                     config.quality,
                     config.iframe_only,
                     config.max_frames,
                     media_q_s.clone(),
                     progress_sender.clone(),
-                );
+                ) {
+                    error!("{:?}", e);
+                }
             });
             drop(media_q_s);
         });
     }
 
-    let frames_clone = Arc::clone(&frames);
-    let export_q_s_clone = export_q_s.clone();
-    let outbound = async_stream::stream! {
-        while let Ok(item) = media_q_r.recv() {
-            match item {
-                WebpItem::Frame(frame) => {
-                    let uuid = Uuid::new_v4().to_string();
-                    let export_frame = ExportFrame {
-                        file: frame.file.clone(),
-                        frame_index: frame.frame_index,
-                        shoot_time: frame.shoot_time.map(|t| t.to_string()),
-                        total_frames: frame.total_frames,
-                        bboxes: None,
-                        label: None,
-                        error: None,
-                    };
-                    frames_clone.lock().unwrap().insert(uuid.clone(), export_frame);
-                    yield DetectRequest { uuid, image: frame.webp, width: frame.width as i32, height: frame.height as i32, iou: config.iou, score: config.conf };
-                }
-                WebpItem::ErrFile(file) => {
-                    export_q_s_clone.send(ExportFrame {
-                        file: file.file.clone(),
-                        frame_index: 0,
-                        shoot_time: None,
-                        total_frames: 0,
-                        bboxes: None,
-                        label: None,
-                        error: Some(file.error.to_string()),
-                    }).unwrap();
-                }
-            }
-        }
-    };
-
-    let mut request = Request::new(outbound);
-    request
-        .metadata_mut()
-        .insert("authorization", session_token.parse().unwrap());
-
-    let response = client.detect(request).await;
-    let mut inbound = match response {
-        Ok(response) => response.into_inner(),
-        Err(status) => {
-            error!("{}", status.message());
-            cleanup_buffer(&config.buffer_path)?;
-            return Ok(());
-        }
-    };
+    let media_q_r = forward_media(media_q_r);
+    let outcome = detect::run(
+        Arc::new(config.clone()),
+        endpoints,
+        Arc::clone(&media_q_r),
+        export_q_s.clone(),
+    )
+    .await;
 
-    loop {
-        match inbound.message().await {
-            Ok(Some(response)) => {
-                let uuid = response.uuid.clone();
-                let mut frames = frames.lock().unwrap();
-                if let Some(mut frame) = frames.remove(&uuid) {
-                    frame.bboxes = Some(
-                        response
-                            .bboxs
-                            .into_iter()
-                            .map(|bbox| Bbox {
-                                x1: bbox.x1,
-                                y1: bbox.y1,
-                                x2: bbox.x2,
-                                y2: bbox.y2,
-                                class: bbox.class as usize,
-                                score: bbox.score,
-                            })
-                            .collect(),
-                    );
-                    frame.label = Some(response.label);
-                    export_q_s.send(frame).unwrap();
-                }
-            }
-            Ok(None) => {
-                drop(export_q_s);
-                while !*finish_clone.lock().unwrap() {
-                    thread::sleep(Duration::from_millis(100));
-                }
-                export::export(&folder_path_clone, export_data_clone, &config.export)?;
-                cleanup_buffer(&config.buffer_path)?;
-                break;
-            }
-            Err(e) => {
-                error!("Error receiving detection: {}", e);
-                drop(export_q_s);
-                while !*finish_clone.lock().unwrap() {
-                    thread::sleep(Duration::from_millis(100));
-                }
-                export::export(&folder_path_clone, export_data_clone, &config.export)?;
-                cleanup_buffer(&config.buffer_path)?;
-                break;
+    if let Some(reason) = &outcome.stop {
+        match reason {
+            Stop::GaveUp => error!(
+                "Giving up, no server is reachable, {} frames unanswered",
+                outcome.unanswered
+            ),
+            Stop::QuotaExceeded(_) => {
+                error!(
+                    "Daily quota exceeded, {} frames unanswered",
+                    outcome.unanswered
+                )
             }
+            Stop::Failed(e) => error!(
+                "Stopping after a detection stream failed, {} frames unanswered: {}",
+                outcome.unanswered, e
+            ),
         }
+        // Let the workers finish the files they are on and skip the rest.
+        cancelled_clone.store(true, Ordering::SeqCst);
+        let mut media_q_r = media_q_r.lock().await;
+        while media_q_r.recv().await.is_some() {}
     }
 
+    drop(export_q_s);
+    while !*finish_clone.lock().unwrap() {
+        thread::sleep(Duration::from_millis(100));
+    }
+    let frames = export_data_clone.lock().unwrap().clone();
+    export::export(export_data_clone, config.export_target(&output_path_clone))?;
+    cleanup_buffer(&config.buffer_path)?;
+
     info!("Elapsed time: {:?}", start.elapsed());
-    Ok(())
+    match outcome.stop {
+        Some(Stop::QuotaExceeded(exceeded)) => Err(quota_error(
+            exceeded,
+            &queued,
+            &frames,
+            &output_path_clone,
+            &config,
+        )),
+        Some(Stop::Failed(e)) => Err(anyhow::anyhow!("Detection stream failed: {}", e)),
+        _ => Ok(()),
+    }
 }
 
-async fn auth(client: &mut Md5rsClient<Channel>, token: &str) -> Result<String> {
-    let response = client
-        .auth(Request::new(AuthRequest {
-            token: token.to_string(),
-        }))
-        .await?;
-    let auth_response = response.into_inner();
-    if auth_response.success {
-        Ok(auth_response.token)
-    } else {
-        Err(anyhow::anyhow!("Auth failed"))
+fn quota_error(
+    exceeded: QuotaExceeded,
+    queued: &[String],
+    frames: &[ExportFrame],
+    output_path: &Path,
+    config: &Config,
+) -> anyhow::Error {
+    let completed = completed_files(frames);
+    ProcessError::QuotaExceeded {
+        remaining: queued.iter().filter(|f| !completed.contains(*f)).count(),
+        resets_in: exceeded.resets_in,
+        checkpoint: export::result_path(output_path, &config.export),
     }
+    .into()
+}
+
+/// Relative paths of the files whose every frame has been answered.
+fn completed_files(frames: &[ExportFrame]) -> HashSet<String> {
+    let frames: Vec<&ExportFrame> = frames.iter().filter(|f| f.error.is_none()).collect();
+    let mut frame_count: HashMap<&str, usize> = HashMap::new();
+    for f in &frames {
+        *frame_count.entry(&f.file.relative_path).or_insert(0) += 1;
+    }
+    frames
+        .iter()
+        .filter(|f| frame_count.get(f.file.relative_path.as_str()) == Some(&f.total_frames))
+        .map(|f| f.file.relative_path.clone())
+        .collect()
+}
+
+/// Point frames read from a checkpoint at the files indexed in this run,
+/// matching their relative path, or the absolute path older checkpoints hold,
+/// so results move with the folder to another drive or machine. Fails when
+/// a frame's relative path is shared by several files.
+fn resolve_frames(frames: &mut [ExportFrame], files: &HashSet<FileItem>) -> Result<()> {
+    let mut by_relative: HashMap<&str, &FileItem> = HashMap::new();
+    let mut ambiguous = HashSet::new();
+    for f in files {
+        if by_relative.insert(f.relative_path.as_str(), f).is_some() {
+            ambiguous.insert(f.relative_path.as_str());
+        }
+    }
+    let by_absolute: HashMap<&Path, &FileItem> =
+        files.iter().map(|f| (f.file_path.as_path(), f)).collect();
+    for frame in frames {
+        if ambiguous.contains(frame.file.relative_path.as_str()) {
+            return Err(ProcessError::AmbiguousCheckpoint {
+                file_path: frame.file.relative_path.clone(),
+            }
+            .into());
+        }
+        let current = by_relative
+            .get(frame.file.relative_path.as_str())
+            .or_else(|| by_absolute.get(Path::new(&frame.file.relative_path)));
+        if let Some(current) = current {
+            let container = frame.file.container;
+            frame.file = (*current).clone();
+            // Files indexed by their extension have no container until decoded.
+            frame.file.container = container.or(frame.file.container);
+        }
+    }
+    Ok(())
 }
 
 fn cleanup_buffer(buffer_path: &Option<String>) -> Result<()> {
@@ -306,46 +591,451 @@ fn resume_from_checkpoint<'a>(
     }
     match checkpoint.extension() {
         Some(ext) => {
-            let ext = ext.to_str().unwrap();
+            let ext = ext.to_string_lossy();
             if ext != "json" && ext != "csv" {
                 error!("Invalid checkpoint file extension: {}", ext);
-                return Err(anyhow::anyhow!(
+                Err(anyhow::anyhow!(
                     "Invalid checkpoint file extension: {}",
                     ext
-                ));
+                ))
             } else {
-                let frames;
-                if ext == "json" {
-                    let json = std::fs::read_to_string(checkpoint)?;
-                    frames = serde_json::from_str(&json)?;
+                let mut frames: Vec<ExportFrame> = if ext == "json" {
+                    parse_export_json(checkpoint)?
                 } else {
-                    frames = parse_export_csv(checkpoint)?;
-                }
-                let mut file_frame_count = HashMap::new();
-                let mut file_total_frames = HashMap::new();
-                for f in &frames {
-                    let file = &f.file;
-                    let count = file_frame_count.entry(file.clone()).or_insert(0);
-                    *count += 1;
-                    file_total_frames
-                        .entry(file.clone())
-                        .or_insert(f.total_frames);
-
-                    if let Some(total_frames) = file_total_frames.get(&file) {
-                        if let Some(frame_count) = file_frame_count.get(&file) {
-                            if total_frames == frame_count {
-                                all_files.remove(&file);
-                            }
-                        }
-                    }
-                }
+                    parse_export_csv(checkpoint)?
+                };
+                resolve_frames(&mut frames, all_files)?;
+                let completed = completed_files(&frames);
+                all_files.retain(|f| !completed.contains(&f.relative_path));
+                // Files queued again are exported afresh, drop their errors.
+                frames.retain(|f| completed.contains(&f.file.relative_path));
                 export_data.lock().unwrap().extend_from_slice(&frames);
                 Ok(all_files)
             }
         }
         None => {
             error!("Invalid checkpoint file extension");
-            return Err(anyhow::anyhow!("Invalid checkpoint file extension"));
+            Err(anyhow::anyhow!("Invalid checkpoint file extension"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+
+    use super::*;
+    use tempfile::TempDir;
+
+    /// A folder of `images` blank JPEGs, removed when dropped.
+    fn test_folder(images: usize) -> TempDir {
+        let folder = tempfile::Builder::new()
+            .prefix("md5rs-test-")
+            .tempdir()
+            .unwrap();
+        for i in 0..images {
+            image::RgbImage::new(64, 48)
+                .save(folder.path().join(format!("IMG_{:04}.jpg", i)))
+                .unwrap();
+        }
+        folder
+    }
+
+    fn test_config(folder: &Path) -> Config {
+        Config {
+            folders: vec![folder.to_path_buf()],
+            urls: vec!["https://localhost".to_string()],
+            token: "token".into(),
+            max_retries: 3,
+            ..Default::default()
+        }
+    }
+
+    /// Run `process` on `config` and read back the frames it exported.
+    async fn run_with<F, Fut>(config: Config, process: F) -> Vec<ExportFrame>
+    where
+        F: FnOnce(Config, crossbeam_channel::Sender<usize>) -> Fut,
+        Fut: Future<Output = Result<()>>,
+    {
+        let folder = config.output_folder().unwrap();
+        let format = config.export;
+        let (progress_sender, progress_receiver) = unbounded();
+        process(config, progress_sender).await.unwrap();
+        drop(progress_receiver);
+        match format {
+            ExportFormat::Json => {
+                let json = std::fs::read_to_string(folder.join("result.json")).unwrap();
+                serde_json::from_str(&json).unwrap()
+            }
+            ExportFormat::Csv => parse_export_csv(folder.join("result.csv")).unwrap(),
+        }
+    }
+
+    async fn run(config: Config, backend: FakeBackend) -> Vec<ExportFrame> {
+        run_with(config, |config, progress_sender| {
+            process_with_backend(config, backend, progress_sender)
+        })
+        .await
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_process_with_fake_backend() {
+        let folder = test_folder(3);
+        let frames = run(test_config(folder.path()), FakeBackend::new()).await;
+        assert_eq!(frames.len(), 3);
+        assert!(frames
+            .iter()
+            .all(|f| f.label == Some(vec!["Blank".to_string()])));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_process_several_roots_and_listed_files() {
+        let (a, b, listed) = (test_folder(2), test_folder(3), test_folder(2));
+        let output = test_folder(0);
+        let mut config = test_config(a.path());
+        config.folders.push(b.path().to_path_buf());
+        // Listed twice, and once more through its root folder.
+        config.files = vec![
+            listed.path().join("IMG_0000.jpg"),
+            a.path().join("IMG_0001.jpg"),
+        ];
+        assert!(matches!(
+            config.output_folder(),
+            Err(ProcessError::OutputRequired)
+        ));
+        config.output = Some(output.path().to_path_buf());
+
+        let frames = run(config, FakeBackend::new()).await;
+        assert_eq!(frames.len(), 6);
+        let ids: HashSet<usize> = frames.iter().map(|f| f.file.file_id).collect();
+        assert_eq!(ids.len(), 6);
+        assert!(!a.path().join("result.json").exists());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_process_by_content() {
+        let folder = test_folder(1);
+        let folder = folder.path();
+        std::fs::rename(folder.join("IMG_0000.jpg"), folder.join("IMG_0000")).unwrap();
+        let mut config = test_config(folder);
+        config.export = ExportFormat::Csv;
+        config.buffer_path = Some(folder.join(".buffer").to_string_lossy().into_owned());
+
+        let frames = run(config, FakeBackend::new()).await;
+        assert_eq!(frames.len(), 1);
+        assert!(frames[0].error.is_none());
+        assert_eq!(frames[0].file.container, Some(Container::Jpeg));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_unusable_buffer_path() {
+        let folder = test_folder(1);
+        let mut config = test_config(folder.path());
+        // Below a file, so the buffer folder cannot be created.
+        let buffer = folder.path().join("IMG_0000.jpg").join(".buffer");
+        config.buffer_path = Some(buffer.to_string_lossy().into_owned());
+
+        let (progress_sender, progress_receiver) = unbounded();
+        let result = process_with_backend(config, FakeBackend::new(), progress_sender).await;
+        drop(progress_receiver);
+        assert!(format!("{:?}", result.unwrap_err()).contains("Failed to create buffer folder"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_process_non_utf8_names() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let folder = test_folder(0);
+        let folder = folder.path();
+        let gbk = |bytes: &[u8]| folder.join(std::ffi::OsStr::from_bytes(bytes));
+        image::RgbImage::new(64, 48)
+            .save_with_format(gbk(b"\xd5\xd5\xc6\xac.jpg"), image::ImageFormat::Jpeg)
+            .unwrap();
+        // Not a video, decoding it fails whether or not ffmpeg is installed.
+        std::fs::write(gbk(b"\xca\xd3\xc6\xb5.mp4"), b"not a video").unwrap();
+        let mut config = test_config(folder);
+        config.buffer_path = Some(folder.join(".buffer").to_string_lossy().into_owned());
+        config.absolute_paths = true;
+
+        let frames = run(config, FakeBackend::new()).await;
+        assert_eq!(frames.len(), 2);
+        let image = frames
+            .iter()
+            .find(|f| f.file.relative_path.ends_with(".jpg"));
+        assert!(image.unwrap().error.is_none());
+        let video = frames
+            .iter()
+            .find(|f| f.file.relative_path.ends_with(".mp4"));
+        assert!(video.unwrap().error.is_some());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_process_against_mock_server() {
+        let mock_config = mock::MockConfig {
+            imgsz: Some(640),
+            encodings: vec!["jpeg".to_string()],
+            ..Default::default()
+        };
+        let addr = mock::spawn(mock_config).await;
+
+        let folder = test_folder(2);
+        let mut config = test_config(folder.path());
+        config.urls = vec![format!("http://{}", addr)];
+        config.compression = Some(Compression::Zstd);
+        config.keepalive = Some(Duration::from_secs(10));
+        config.connect_timeout = Some(Duration::from_secs(5));
+        config.stream_window = Some(1 << 20);
+        config.connection_window = Some(4 << 20);
+        let frames = run_with(config, process).await;
+        assert_eq!(frames.len(), 2);
+        assert!(frames.iter().all(|f| f.label.is_some()));
+        let model = format!(
+            "MegaDetector v5a (md5rs-server mock {})",
+            env!("CARGO_PKG_VERSION")
+        );
+        assert!(frames.iter().all(|f| f.model.as_ref() == Some(&model)));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_batch_frames_to_mock_server() {
+        let mock_config = mock::MockConfig {
+            max_batch: Some(4),
+            ..Default::default()
+        };
+        let addr = mock::spawn(mock_config).await;
+
+        let folder = test_folder(7);
+        let mut config = test_config(folder.path());
+        config.urls = vec![format!("http://{}", addr)];
+        // Larger than the server takes, so batches are capped at 4.
+        config.batch_size = 8;
+        let frames = run_with(config, process).await;
+        assert_eq!(frames.len(), 7);
+        assert!(frames
+            .iter()
+            .all(|f| f.label.is_some() && f.error.is_none()));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_refresh_expiring_session() {
+        let mock_config = mock::MockConfig {
+            session_ttl: Some(Duration::from_secs(2)),
+            latency: Duration::from_millis(300),
+            ..Default::default()
+        };
+        let addr = mock::spawn(mock_config).await;
+
+        let folder = test_folder(8);
+        let mut config = test_config(folder.path());
+        config.urls = vec![format!("http://{}", addr)];
+        let frames = run_with(config, process).await;
+        assert_eq!(frames.len(), 8);
+        assert!(frames.iter().all(|f| f.label.is_some()));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_reauth_when_session_expires() {
+        let folder = test_folder(4);
+        let backend = FakeBackend::new().expire_after(2);
+        let frames = run(test_config(folder.path()), backend.clone()).await;
+        assert_eq!(backend.streams_opened(), 2);
+        assert_eq!(frames.len(), 4);
+        assert!(frames.iter().all(|f| f.label.is_some()));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_resume_from_json_checkpoint() {
+        let folder = test_folder(3);
+        let frames = run(test_config(folder.path()), FakeBackend::new()).await;
+        let scratch = test_folder(0);
+        let checkpoint = scratch.path().join("checkpoint.json");
+        std::fs::write(&checkpoint, serde_json::to_string(&frames[..2]).unwrap()).unwrap();
+
+        let mut config = test_config(folder.path());
+        config.resume_from = Some(checkpoint.to_string_lossy().into_owned());
+        let frames = run(config, FakeBackend::new()).await;
+        assert_eq!(frames.len(), 3);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_resume_after_moving_folder() {
+        let folder = test_folder(3);
+        let frames = run(test_config(folder.path()), FakeBackend::new()).await;
+        assert!(frames
+            .iter()
+            .all(|f| f.file.relative_path.starts_with("IMG_")
+                && f.file.file_path.as_os_str().is_empty()));
+        let scratch = test_folder(0);
+        let checkpoint = scratch.path().join("checkpoint.json");
+        std::fs::write(&checkpoint, serde_json::to_string(&frames[..2]).unwrap()).unwrap();
+
+        // The folder is mounted elsewhere; only the last file is sent again.
+        let moved = scratch.path().join("moved");
+        std::fs::rename(folder.path(), &moved).unwrap();
+        let backend = FakeBackend::new();
+        let mut config = test_config(&moved);
+        config.resume_from = Some(checkpoint.to_string_lossy().into_owned());
+        config.absolute_paths = true;
+        let frames = run(config, backend.clone()).await;
+        assert_eq!(backend.frames_received(), 1);
+        assert_eq!(frames.len(), 3);
+        let moved = std::fs::canonicalize(&moved).unwrap();
+        assert!(frames
+            .iter()
+            .all(|f| f.file.file_path == moved.join(&f.file.relative_path)));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_stop_when_quota_exceeded() {
+        let folder = test_folder(5);
+        let backend = FakeBackend::new().with_quota(2, Duration::from_secs(3600));
+        let (progress_sender, progress_receiver) = unbounded();
+        let e = process_with_backend(test_config(folder.path()), backend, progress_sender)
+            .await
+            .unwrap_err();
+        drop(progress_receiver);
+        let checkpoint = folder.path().join("result.json");
+        match e.downcast_ref::<ProcessError>() {
+            Some(ProcessError::QuotaExceeded {
+                remaining,
+                resets_in,
+                checkpoint: path,
+            }) => {
+                assert_eq!(*remaining, 3);
+                assert_eq!(*resets_in, Some(Duration::from_secs(3600)));
+                assert_eq!(path, &checkpoint);
+            }
+            _ => panic!("unexpected error {:?}", e),
+        }
+        let json = std::fs::read_to_string(&checkpoint).unwrap();
+        let frames: Vec<ExportFrame> = serde_json::from_str(&json).unwrap();
+        assert_eq!(frames.len(), 2);
+
+        let mut config = test_config(folder.path());
+        config.resume_from = Some(checkpoint.to_string_lossy().into_owned());
+        let frames = run(config, FakeBackend::new()).await;
+        assert_eq!(frames.len(), 5);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_resend_unanswered_frames() {
+        let folder = test_folder(4);
+        let mut config = test_config(folder.path());
+        config.frame_timeout = Duration::from_millis(500);
+        let frames = run(config, FakeBackend::new().ignore_first(2)).await;
+        assert_eq!(frames.len(), 4);
+        assert!(frames
+            .iter()
+            .all(|f| f.error.is_none() && f.label.is_some()));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_export_timeout_error() {
+        let folder = test_folder(1);
+        let mut config = test_config(folder.path());
+        config.frame_timeout = Duration::from_millis(200);
+        config.frame_retries = 1;
+        let frames = run(config, FakeBackend::new().ignore_first(2)).await;
+        assert_eq!(frames.len(), 1);
+        assert!(frames[0].label.is_none());
+        assert_eq!(
+            frames[0].error.as_deref(),
+            Some("No response from server after 2 attempts")
+        );
+    }
+
+    #[test]
+    fn test_resolve_ambiguous_frames() {
+        let file = |path: &str| {
+            let mut file = FileItem::new(0, 0, PathBuf::from(path), None);
+            file.relative_path = "card/IMG_0001.JPG".to_string();
+            file
+        };
+        let files = HashSet::from([file("/x/card/IMG_0001.JPG"), file("/y/card/IMG_0001.JPG")]);
+        let mut frames = vec![ExportFrame {
+            file: FileItem::new(0, 0, PathBuf::from("card/IMG_0001.JPG"), None),
+            frame_index: 0,
+            shoot_time: None,
+            total_frames: 1,
+            bboxes: Some(Vec::new()),
+            label: Some(vec!["Blank".to_string()]),
+            error: None,
+            model: None,
+        }];
+        let e = resolve_frames(&mut frames, &files).unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<ProcessError>(),
+            Some(ProcessError::AmbiguousCheckpoint { .. })
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_resume_after_timeout_error() {
+        let folder = test_folder(2);
+        let mut config = test_config(folder.path());
+        config.frame_timeout = Duration::from_millis(200);
+        config.frame_retries = 0;
+        let frames = run(config, FakeBackend::new().ignore_first(1)).await;
+        assert_eq!(frames.iter().filter(|f| f.error.is_some()).count(), 1);
+        let scratch = test_folder(0);
+        let checkpoint = scratch.path().join("checkpoint.json");
+        std::fs::write(&checkpoint, serde_json::to_string(&frames).unwrap()).unwrap();
+
+        let mut config = test_config(folder.path());
+        config.resume_from = Some(checkpoint.to_string_lossy().into_owned());
+        let frames = run(config, FakeBackend::new()).await;
+        assert_eq!(frames.len(), 2);
+        assert!(frames.iter().all(|f| f.error.is_none()));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_resend_after_disconnect() {
+        let folder = test_folder(4);
+        let backend = FakeBackend::new().disconnect_after(1);
+        let frames = run(test_config(folder.path()), backend.clone()).await;
+        assert_eq!(backend.streams_opened(), 2);
+        assert_eq!(frames.len(), 4);
+        assert!(frames
+            .iter()
+            .all(|f| f.error.is_none() && f.label.is_some()));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_fail_over_to_healthy_server() {
+        let folder = test_folder(6);
+        let mut config = test_config(folder.path());
+        config.max_retries = 0;
+        config.streams_per_server = 2;
+        let failing = FakeBackend::new().disconnect_after(1);
+        let healthy = FakeBackend::new();
+        let backends = vec![failing, healthy.clone()];
+        let frames = run_with(config, |config, progress_sender| {
+            process_with_backends(config, backends, progress_sender)
+        })
+        .await;
+        assert_eq!(frames.len(), 6);
+        assert!(frames
+            .iter()
+            .all(|f| f.error.is_none() && f.label.is_some()));
+        assert!(healthy.streams_opened() >= 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_quota_exceeded_on_one_server() {
+        // Out of quota at auth, then part way through the run.
+        for frames in [0, 2] {
+            let folder = test_folder(6);
+            let exhausted = FakeBackend::new().with_quota(frames, Duration::from_secs(3600));
+            let healthy = FakeBackend::new();
+            let backends = vec![exhausted, healthy.clone()];
+            let frames = run_with(test_config(folder.path()), |config, progress_sender| {
+                process_with_backends(config, backends, progress_sender)
+            })
+            .await;
+            assert_eq!(frames.len(), 6);
+            assert!(frames.iter().all(|f| f.error.is_none()));
+            assert!(healthy.streams_opened() >= 1);
         }
     }
 }
@@ -1,28 +1,35 @@
+use std::io::{self, Write};
+use std::sync::Arc;
+
 use tracing_appender::{non_blocking, rolling};
 use tracing_error::ErrorLayer;
 use tracing_subscriber::{
-    fmt, fmt::time::OffsetTime, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer,
-    Registry,
+    fmt, fmt::time::OffsetTime, fmt::MakeWriter, layer::SubscriberExt, util::SubscriberInitExt,
+    EnvFilter, Layer, Registry,
 };
 
+/// `secrets` such as the access token are replaced by `***` in every log line.
 pub fn init_logger(
     log_level: String,
     log_file: String,
+    secrets: Vec<String>,
 ) -> anyhow::Result<non_blocking::WorkerGuard> {
     let filter = EnvFilter::from_default_env()
         .add_directive(format!("md5rs_client={}", log_level.to_lowercase()).parse()?)
         .add_directive("nom-exif=off".parse()?);
+    let secrets: Arc<Vec<String>> =
+        Arc::new(secrets.into_iter().filter(|s| !s.is_empty()).collect());
 
     let formatting_layer = fmt::layer()
         .pretty()
         .with_timer(OffsetTime::local_rfc_3339().expect("could not get local offset!"))
-        .with_writer(std::io::stderr);
+        .with_writer(Redact::new(std::io::stderr, &secrets));
 
     let file_appender = rolling::daily("logs/", log_file.as_str()); // 每天一个日志文件
     let (non_blocking_appender, guard) = non_blocking(file_appender); // 输出非阻塞
     let file_layer = fmt::layer()
         .with_ansi(false)
-        .with_writer(non_blocking_appender) // 文件输出日志等级
+        .with_writer(Redact::new(non_blocking_appender, &secrets)) // 文件输出日志等级
         .boxed();
 
     Registry::default()
@@ -34,3 +41,65 @@ pub fn init_logger(
 
     Ok(guard)
 }
+
+/// Wraps a log writer to mask secrets in each formatted event.
+struct Redact<M> {
+    inner: M,
+    secrets: Arc<Vec<String>>,
+}
+
+impl<M> Redact<M> {
+    fn new(inner: M, secrets: &Arc<Vec<String>>) -> Self {
+        Redact {
+            inner,
+            secrets: Arc::clone(secrets),
+        }
+    }
+}
+
+impl<'a, M: MakeWriter<'a>> MakeWriter<'a> for Redact<M> {
+    type Writer = Redact<M::Writer>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        Redact::new(self.inner.make_writer(), &self.secrets)
+    }
+}
+
+impl<W: Write> Write for Redact<W> {
+    // tracing-subscriber formats an event into one buffer before writing it,
+    // so a secret is never split across two calls.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let text = String::from_utf8_lossy(buf);
+        if !self.secrets.iter().any(|s| text.contains(s.as_str())) {
+            self.inner.write_all(buf)?;
+            return Ok(buf.len());
+        }
+        let mut text = text.into_owned();
+        for secret in self.secrets.iter() {
+            text = text.replace(secret.as_str(), "***");
+        }
+        self.inner.write_all(text.as_bytes())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact_secrets() {
+        let secrets = Arc::new(vec!["s3cr3t".to_string()]);
+        let mut out = Redact::new(Vec::new(), &secrets);
+        write!(out, "auth with s3cr3t failed, s3cr3t rejected").unwrap();
+        write!(out, " done").unwrap();
+        assert_eq!(
+            String::from_utf8(out.inner).unwrap(),
+            "auth with *** failed, *** rejected done"
+        );
+    }
+}
@@ -1,19 +1,81 @@
-use clap::{Parser, ValueEnum};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
 use indicatif::{ProgressBar, ProgressStyle};
 use std::path::PathBuf;
+use std::time::Duration;
 use tracing::error;
 
-use md5rs_client::{log, process, Config, ExportFormat};
+use md5rs_client::check::Status as CheckStatus;
+use md5rs_client::profile::{self, Profile};
+use md5rs_client::utils::DEFAULT_SKIP_DIRS;
+use md5rs_client::{
+    log, process, tls, token, Compression, Config, Discovery, ExportFormat, IdMap, IdScheme,
+    ProcessError, TlsRoots, Token,
+};
+
+/// Exit code when the run stopped because the daily quota ran out.
+const EXIT_QUOTA_EXCEEDED: i32 = 3;
 
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(
+    version,
+    about,
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Folder to process, may be repeated
+    #[arg(short, long, required_unless_present = "files_from")]
+    folder: Vec<PathBuf>,
+    /// Text file listing files to process, one per line, or - for stdin
+    #[arg(long)]
+    files_from: Option<PathBuf>,
+    /// Folder to write the results to, required with several folders or --files-from
     #[arg(short, long)]
-    folder: String,
+    output: Option<PathBuf>,
+    /// Only process files matching this glob, relative to the folder. May be repeated
+    #[arg(long)]
+    include: Vec<String>,
+    /// Leave out files and folders matching this glob, relative to the folder. May be repeated
+    #[arg(long)]
+    exclude: Vec<String>,
+    /// Deepest folder level searched, 1 for the files directly in the folder
+    #[arg(long)]
+    max_depth: Option<usize>,
+    /// Follow symbolic links to files and folders
+    #[arg(long)]
+    follow_symlinks: bool,
+    /// Skip files smaller than this, in bytes or with a K, M or G suffix
+    #[arg(long, value_parser = parse_size)]
+    min_size: Option<u64>,
+    /// Skip files larger than this, in bytes or with a K, M or G suffix
+    #[arg(long, value_parser = parse_size)]
+    max_size: Option<u64>,
+    /// Folder names never searched, replacing the default list. May be repeated
+    #[arg(long, default_values = DEFAULT_SKIP_DIRS)]
+    skip_dir: Vec<String>,
+    /// How files and folders are numbered in the results
+    #[arg(long, value_enum, default_value_t = CliIdScheme::Sequential)]
+    ids: CliIdScheme,
+    /// JSON file keeping file and folder ids across runs, created if missing
+    #[arg(long)]
+    id_map: Option<String>,
+    /// Also write the absolute path of every file, besides the path relative to the folder
+    #[arg(long)]
+    absolute_paths: bool,
+    /// md5rs server, may be repeated to spread the work over several servers
     #[arg(short, long, default_value = "https://md5rs.hinature.cn")]
-    url: String,
-    #[arg(short, long)]
-    token: String,
+    url: Vec<String>,
+    /// Detection streams opened to each server
+    #[arg(long, default_value_t = 1)]
+    streams: usize,
+    #[command(flatten)]
+    token: TokenArgs,
+    #[command(flatten)]
+    profile: ProfileArgs,
     #[arg(long, default_value = "3")]
     max_frames: Option<usize>,
     #[arg(long, short, default_value_t = true)]
@@ -38,6 +100,157 @@ struct Args {
     buffer_path: Option<String>,
     #[arg(long, default_value_t = 20)]
     buffer_size: usize,
+    #[arg(long, default_value_t = 5)]
+    max_retries: usize,
+    /// Seconds to wait for the detection of a frame before sending it again
+    #[arg(long, default_value_t = 60)]
+    frame_timeout: u64,
+    /// Times an unanswered frame is sent again before it is exported with an error
+    #[arg(long, default_value_t = 2)]
+    frame_retries: usize,
+    /// Most frames awaiting a detection at once
+    #[arg(long, default_value_t = 64)]
+    max_in_flight: usize,
+    /// Keep --max-in-flight frames in flight instead of tuning the window from round-trip times
+    #[arg(long)]
+    fixed_window: bool,
+    /// Compress gRPC messages
+    #[arg(long, value_enum)]
+    compression: Option<CliCompression>,
+    /// Seconds between HTTP/2 keepalive pings
+    #[arg(long)]
+    keepalive: Option<u64>,
+    /// Seconds to wait for a connection to a server
+    #[arg(long)]
+    connect_timeout: Option<u64>,
+    /// Initial HTTP/2 flow control window of each stream, in bytes
+    #[arg(long)]
+    stream_window: Option<u32>,
+    /// Initial HTTP/2 flow control window of the connection, in bytes
+    #[arg(long)]
+    connection_window: Option<u32>,
+    /// Frames sent per message to servers supporting batches, 1 to send them one by one
+    #[arg(long, default_value_t = 1)]
+    batch_size: usize,
+    /// Milliseconds a frame may wait for its batch to fill up
+    #[arg(long, default_value_t = 50)]
+    batch_latency_ms: u64,
+    #[command(flatten)]
+    tls: TlsArgs,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print the SHA-256 fingerprints of the server's certificate chain for --pin
+    Pin {
+        #[arg(short, long, default_value = "https://md5rs.hinature.cn")]
+        url: String,
+        #[command(flatten)]
+        tls: TlsArgs,
+    },
+    /// Check DNS, TLS, the access token, a test detection and ffmpeg, and suggest fixes.
+    /// The test detection counts against the daily quota
+    Check {
+        /// md5rs server, may be repeated
+        #[arg(short, long, default_value = "https://md5rs.hinature.cn")]
+        url: Vec<String>,
+        #[command(flatten)]
+        token: TokenArgs,
+        #[command(flatten)]
+        profile: ProfileArgs,
+        #[command(flatten)]
+        tls: TlsArgs,
+    },
+    /// Manage config files
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigCommand {
+    /// Write a commented config file with the default settings
+    Init {
+        #[arg(default_value = "md5rs.toml")]
+        path: PathBuf,
+        /// Overwrite an existing file
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[derive(clap::Args, Debug)]
+struct ProfileArgs {
+    /// TOML config file with [profiles.<name>] tables, see `config init`
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// Profile of --config to use, "default" if not given
+    #[arg(long, requires = "config")]
+    profile: Option<String>,
+}
+
+impl ProfileArgs {
+    fn load(&self) -> anyhow::Result<Profile> {
+        Ok(match &self.config {
+            Some(path) => profile::load(path, self.profile.as_deref().unwrap_or("default"))?,
+            None => Profile::default(),
+        })
+    }
+}
+
+#[derive(clap::Args, Debug)]
+struct TokenArgs {
+    /// Access token, visible to other users of the machine in `ps` output.
+    /// Prefer MD5RS_TOKEN, --token-file or a config profile, which take precedence
+    #[arg(short, long)]
+    token: Option<String>,
+    /// File holding the access token
+    #[arg(long)]
+    token_file: Option<PathBuf>,
+}
+
+impl TokenArgs {
+    fn resolve(&self, profile: &Profile) -> anyhow::Result<Token> {
+        Ok(token::resolve(
+            self.token_file.as_deref(),
+            profile,
+            self.token.as_deref(),
+        )?)
+    }
+}
+
+#[derive(clap::Args, Debug)]
+struct TlsArgs {
+    /// Certificates trusted for the server, ignored for http:// URLs
+    #[arg(long, value_enum, default_value_t = CliTlsRoots::System)]
+    tls_roots: CliTlsRoots,
+    /// PEM CA bundle to trust instead of --tls-roots
+    #[arg(long)]
+    ca_file: Option<String>,
+    /// PEM client certificate for mutual TLS, requires --client-key
+    #[arg(long, requires = "client_key")]
+    client_cert: Option<String>,
+    /// PEM private key for --client-cert
+    #[arg(long, requires = "client_cert")]
+    client_key: Option<String>,
+    /// Refuse to connect unless the server chain matches this SHA-256 fingerprint,
+    /// either sha256/<base64 SPKI hash> or a hex certificate hash. May be repeated
+    #[arg(long)]
+    pin: Vec<String>,
+    /// Proxy to connect through, http://, socks5:// or socks5h:// with optional
+    /// user:pass@. Defaults to HTTPS_PROXY or ALL_PROXY unless NO_PROXY matches
+    #[arg(long)]
+    proxy: Option<String>,
+}
+
+impl TlsArgs {
+    fn roots(&self) -> TlsRoots {
+        match &self.ca_file {
+            Some(ca_file) => TlsRoots::CaFile(ca_file.clone()),
+            None => self.tls_roots.into(),
+        }
+    }
 }
 
 #[derive(ValueEnum, Debug, Clone, Copy)]
@@ -46,6 +259,53 @@ enum CliExportFormat {
     Csv,
 }
 
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum CliIdScheme {
+    /// Numbered in the order files are found
+    Sequential,
+    /// Hash of the path below the parent of the processed folder, the same on every run
+    PathHash,
+}
+
+impl From<CliIdScheme> for IdScheme {
+    fn from(s: CliIdScheme) -> Self {
+        match s {
+            CliIdScheme::Sequential => IdScheme::Sequential,
+            CliIdScheme::PathHash => IdScheme::PathHash,
+        }
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum CliCompression {
+    Gzip,
+    Zstd,
+}
+
+impl From<CliCompression> for Compression {
+    fn from(c: CliCompression) -> Self {
+        match c {
+            CliCompression::Gzip => Compression::Gzip,
+            CliCompression::Zstd => Compression::Zstd,
+        }
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum CliTlsRoots {
+    System,
+    Bundled,
+}
+
+impl From<CliTlsRoots> for TlsRoots {
+    fn from(r: CliTlsRoots) -> Self {
+        match r {
+            CliTlsRoots::System => TlsRoots::System,
+            CliTlsRoots::Bundled => TlsRoots::Bundled,
+        }
+    }
+}
+
 impl From<CliExportFormat> for ExportFormat {
     fn from(f: CliExportFormat) -> Self {
         match f {
@@ -55,17 +315,102 @@ impl From<CliExportFormat> for ExportFormat {
     }
 }
 
+/// Parse a size like `500`, `200K`, `5M` or `2G`, with binary multiples.
+fn parse_size(s: &str) -> Result<u64, String> {
+    let (digits, unit) = match s.trim().char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => (&s.trim()[..i], c.to_ascii_uppercase()),
+        _ => (s.trim(), 'B'),
+    };
+    let multiple = match unit {
+        'B' => 1,
+        'K' => 1 << 10,
+        'M' => 1 << 20,
+        'G' => 1 << 30,
+        _ => return Err(format!("unknown size unit {}, use K, M or G", unit)),
+    };
+    digits
+        .parse::<u64>()
+        .map_err(|e| format!("invalid size {}: {}", s, e))?
+        .checked_mul(multiple)
+        .ok_or_else(|| format!("size {} is too large", s))
+}
+
+/// Whether the option with id `name` was given explicitly, so it wins over the
+/// config profile. Options the command does not take were not given.
+fn on_command_line(matches: &ArgMatches, name: &str) -> bool {
+    matches.ids().any(|id| id.as_str() == name)
+        && matches.value_source(name) == Some(ValueSource::CommandLine)
+}
+
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
-    let args = Args::parse();
+    let matches = Args::command().get_matches();
+    let args = Args::from_arg_matches(&matches)?;
+
+    if let Some(Command::Pin { url, tls }) = &args.command {
+        return pin(url.clone(), tls.roots(), tls.proxy.clone()).await;
+    }
+    if let Some(Command::Config {
+        command: ConfigCommand::Init { path, force },
+    }) = &args.command
+    {
+        profile::init(path, *force)?;
+        println!("Wrote {}", path.display());
+        return Ok(());
+    }
+    if let Some(Command::Check {
+        url,
+        token,
+        profile,
+        tls,
+    }) = args.command
+    {
+        let profile = profile.load()?;
+        let mut config = Config {
+            urls: url,
+            token: token.resolve(&profile)?,
+            tls_roots: tls.roots(),
+            client_cert: tls.client_cert,
+            client_key: tls.client_key,
+            pins: tls.pin,
+            proxy: tls.proxy,
+            connect_timeout: Some(Duration::from_secs(10)),
+            ..Default::default()
+        };
+        if let Some(matches) = matches.subcommand_matches("check") {
+            profile.apply(&mut config, |name| on_command_line(matches, name));
+        }
+        std::process::exit(check(&config).await);
+    }
     let (progress_sender, progress_receiver) = crossbeam_channel::unbounded();
 
-    let guard = log::init_logger(args.log_level, args.log_file).expect("Failed to init logger");
+    let profile = args.profile.load()?;
+    let token = args.token.resolve(&profile)?;
+    let files = match &args.files_from {
+        Some(list) => md5rs_client::utils::read_file_list(list)?,
+        None => Vec::new(),
+    };
+    let guard = log::init_logger(args.log_level, args.log_file, vec![token.to_string()])
+        .expect("Failed to init logger");
 
-    let config = Config {
-        folder: args.folder,
-        url: args.url,
-        token: args.token,
+    let mut config = Config {
+        folders: args.folder,
+        files,
+        output: args.output,
+        discovery: Discovery {
+            include: args.include,
+            exclude: args.exclude,
+            max_depth: args.max_depth,
+            follow_symlinks: args.follow_symlinks,
+            min_size: args.min_size,
+            max_size: args.max_size,
+            skip_dirs: args.skip_dir,
+        },
+        ids: args.ids.into(),
+        id_map: args.id_map,
+        absolute_paths: args.absolute_paths,
+        urls: args.url,
+        token,
         max_frames: args.max_frames,
         iframe_only: args.iframe_only,
         iou: args.iou,
@@ -76,10 +421,36 @@ async fn main() -> Result<(), anyhow::Error> {
         resume_from: args.resume_from,
         buffer_path: args.buffer_path,
         buffer_size: args.buffer_size,
+        max_retries: args.max_retries,
+        frame_timeout: Duration::from_secs(args.frame_timeout),
+        frame_retries: args.frame_retries,
+        max_in_flight: args.max_in_flight,
+        adaptive_window: !args.fixed_window,
+        streams_per_server: args.streams,
+        tls_roots: args.tls.roots(),
+        client_cert: args.tls.client_cert,
+        client_key: args.tls.client_key,
+        pins: args.tls.pin,
+        proxy: args.tls.proxy,
+        compression: args.compression.map(Into::into),
+        keepalive: args.keepalive.map(Duration::from_secs),
+        connect_timeout: args.connect_timeout.map(Duration::from_secs),
+        stream_window: args.stream_window,
+        connection_window: args.connection_window,
+        batch_size: args.batch_size,
+        batch_latency: Duration::from_millis(args.batch_latency_ms),
     };
+    profile.apply(&mut config, |name| on_command_line(&matches, name));
 
-    let total_files =
-        md5rs_client::utils::index_files_and_folders(&PathBuf::from(&config.folder)).len();
+    config.output_folder()?;
+    let total_files = md5rs_client::utils::index_inputs(
+        &config.folders,
+        &config.files,
+        &config.discovery,
+        config.ids,
+        &mut IdMap::default(),
+    )?
+    .len();
     let pb = ProgressBar::new(total_files as u64);
     pb.set_style(ProgressStyle::default_bar().template(
         "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({eta})",
@@ -92,16 +463,58 @@ async fn main() -> Result<(), anyhow::Error> {
         pb.finish();
     });
 
-    match process(config, progress_sender).await {
-        Ok(_) => {}
+    let result = process(config, progress_sender).await;
+
+    progress_thread.join().unwrap();
+
+    let exit_code = match result {
+        Ok(_) => 0,
         Err(e) => {
             error!("Error: {:?}", e);
+            match e.downcast_ref::<ProcessError>() {
+                Some(ProcessError::QuotaExceeded { .. }) => EXIT_QUOTA_EXCEEDED,
+                Some(ProcessError::OutputRequired | ProcessError::AmbiguousCheckpoint { .. })
+                | None => 1,
+            }
         }
-    }
-
-    progress_thread.join().unwrap();
+    };
 
     drop(guard);
 
+    std::process::exit(exit_code);
+}
+
+/// Run the checks for every server and return the exit code.
+async fn check(config: &Config) -> i32 {
+    let mut passed = true;
+    for url in &config.urls {
+        let checks = md5rs_client::check::run(config, url).await;
+        passed &= checks.iter().all(|c| c.status != CheckStatus::Fail);
+        println!("{}", url);
+        println!("{}", md5rs_client::check::render(&checks));
+    }
+    if passed {
+        0
+    } else {
+        1
+    }
+}
+
+async fn pin(url: String, roots: TlsRoots, proxy: Option<String>) -> Result<(), anyhow::Error> {
+    // The certificate is fetched over a blocking socket.
+    let fingerprints = {
+        let url = url.clone();
+        tokio::task::spawn_blocking(move || {
+            tls::server_fingerprints(&url, &roots, proxy.as_deref())
+        })
+        .await??
+    };
+    println!("Certificate chain presented by {}:", url);
+    for (i, fingerprint) in fingerprints.iter().enumerate() {
+        let position = if i == 0 { "leaf" } else { "issuer" };
+        println!("#{} {}", i, position);
+        println!("  --pin {}", fingerprint.spki);
+        println!("  --pin {}", fingerprint.certificate);
+    }
     Ok(())
 }
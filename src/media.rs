@@ -1,24 +1,28 @@
+use std::cell::Cell;
 use std::fs::{metadata, File};
 use std::io::BufReader;
+use std::panic::{self, AssertUnwindSafe};
 use std::path::{Path, PathBuf};
+use std::sync::Once;
 use std::thread;
 use std::time::Duration;
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use chrono::{DateTime, Local};
 use crossbeam_channel::Sender;
 use fast_image_resize::{ResizeAlg, ResizeOptions, Resizer};
 use ffmpeg_sidecar::command::FfmpegCommand;
 use ffmpeg_sidecar::event::{FfmpegEvent, LogLevel};
 use ffmpeg_sidecar::iter::FfmpegIterator;
-use image::{DynamicImage, GenericImageView, ImageReader};
+use image::codecs::jpeg::JpegEncoder;
+use image::{DynamicImage, GenericImageView, ImageReader, RgbImage};
 use jpeg_decoder::Decoder;
 use nom_exif::{Exif, ExifIter, ExifTag, MediaParser, MediaSource};
 use thiserror::Error;
 use tracing::{debug, error, warn};
 use webp::Encoder;
 
-use crate::utils::{sample_evenly, FileItem};
+use crate::utils::{sample_evenly, FileItem, MediaKind};
 
 //define meadia error
 #[derive(Error, Debug)]
@@ -35,13 +39,64 @@ pub enum MediaError {
     #[error("Failed to encode: {0}")]
     WebpEncodeError(String),
 
+    #[error("Failed to encode: {0}")]
+    JpegEncodeError(#[from] image::ImageError),
+
     #[error("Ffmpeg error when decoding {1}: {0}")]
     FfmpegError(String, String),
 }
 
+/// Image encoding of the frames sent to the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Webp,
+    Jpeg,
+}
+
+impl Encoding {
+    /// Encodings in order of preference.
+    pub const ALL: [Encoding; 2] = [Encoding::Webp, Encoding::Jpeg];
+
+    /// Name of the encoding in `DetectRequest` and `ServerInfoResponse`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Webp => "webp",
+            Encoding::Jpeg => "jpeg",
+        }
+    }
+
+    pub fn encode(&self, img: &DynamicImage, quality: f32) -> Result<Vec<u8>> {
+        match self {
+            Encoding::Webp => match Encoder::from_image(img) {
+                Ok(encoder) => Ok(encoder.encode(quality).to_vec()),
+                Err(e) => {
+                    error!("Failed to encode image: {:?}", e);
+                    Err(MediaError::WebpEncodeError(e.to_string()).into())
+                }
+            },
+            Encoding::Jpeg => {
+                let mut jpeg = Vec::new();
+                JpegEncoder::new_with_quality(&mut jpeg, quality.clamp(1.0, 100.0) as u8)
+                    .encode_image(&img.to_rgb8())
+                    .map_err(MediaError::from)?;
+                Ok(jpeg)
+            }
+        }
+    }
+}
+
+/// How frames are resized and encoded for the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameFormat {
+    /// Longest side of a frame, in pixels.
+    pub imgsz: usize,
+    pub encoding: Encoding,
+}
+
 pub struct Frame {
     pub file: FileItem,
-    pub webp: Vec<u8>,
+    pub image: Vec<u8>,
+    pub encoding: Encoding,
     pub width: usize,
     pub height: usize,
     pub frame_index: usize,
@@ -60,33 +115,97 @@ pub enum WebpItem {
 }
 
 pub fn media_worker(
-    file: FileItem,
-    imgsz: usize,
+    mut file: FileItem,
+    format: FrameFormat,
     quality: f32,
     iframe: bool,
     max_frames: Option<usize>,
     array_q_s: Sender<WebpItem>,
     progress_sender: Sender<usize>,
-) {
-    let mut parser = MediaParser::new();
-    let mut resizer = Resizer::new();
-    if let Some(extension) = file.file_path.extension() {
-        let array_q_s = array_q_s.clone();
-        match extension.to_str().unwrap().to_lowercase().as_str() {
-            "jpg" | "jpeg" | "png" => {
-                process_image(&file, imgsz, quality, &mut parser, &mut resizer, array_q_s).unwrap();
-            }
-            "mp4" | "avi" | "mkv" | "mov" => {
-                process_video(&file, imgsz, quality, iframe, max_frames, array_q_s).unwrap();
-            }
-            _ => (),
+) -> Result<()> {
+    file.identify();
+    let iframe = file.settings.iframe_only.unwrap_or(iframe);
+    let max_frames = file.settings.max_frames.or(max_frames);
+    let result = catch_panic(&file, || {
+        let mut parser = MediaParser::new();
+        let mut resizer = Resizer::new();
+        match file.kind() {
+            Some(MediaKind::Image) => process_image(
+                &file,
+                format,
+                quality,
+                &mut parser,
+                &mut resizer,
+                array_q_s.clone(),
+            ),
+            Some(MediaKind::Video) => process_video(
+                &file,
+                format,
+                quality,
+                iframe,
+                max_frames,
+                array_q_s.clone(),
+            ),
+            None => Err(anyhow!(
+                "Not a supported photo or video: {}",
+                file.file_path.display()
+            )),
         }
-        if &file.file_path != &file.tmp_path {
-            remove_file_with_retries(&file.tmp_path, 3, Duration::from_secs(1))
-                .expect("Failed to remove file");
+    });
+    if let Err(error) = result {
+        error!("{:?}", error);
+        let err_file = WebpItem::ErrFile(ErrFile {
+            file: file.clone(),
+            error,
+        });
+        if array_q_s.send(err_file).is_err() {
+            error!("Failed to send frame data, channel disconnected");
         }
-        progress_sender.send(1).expect("Send progress failed");
     }
+    if file.file_path != file.tmp_path {
+        remove_file_with_retries(&file.tmp_path, 3, Duration::from_secs(1))?;
+    }
+    progress_sender.send(1).context("Send progress failed")
+}
+
+thread_local! {
+    /// Whether this thread is inside [`catch_panic`].
+    static CATCHING_PANIC: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Run `process` for `file`, turning a panic into an error so one bad file
+/// cannot take down a worker and leave the run waiting for it.
+///
+/// The panic hook is global, so rather than swapping it around each call a
+/// hook is installed once that stays quiet on threads inside `catch_panic`
+/// (the error reports the panic instead) and defers to the previous hook
+/// everywhere else.
+fn catch_panic(file: &FileItem, process: impl FnOnce() -> Result<()>) -> Result<()> {
+    static QUIET_HOOK: Once = Once::new();
+    QUIET_HOOK.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if !CATCHING_PANIC.with(Cell::get) {
+                previous(info);
+            }
+        }));
+    });
+
+    CATCHING_PANIC.with(|catching| catching.set(true));
+    let result = panic::catch_unwind(AssertUnwindSafe(process));
+    CATCHING_PANIC.with(|catching| catching.set(false));
+    result.unwrap_or_else(|payload| {
+        let message = payload
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unknown cause");
+        Err(anyhow!(
+            "Panicked while processing {}: {}",
+            file.file_path.display(),
+            message
+        ))
+    })
 }
 
 fn remove_file_with_retries(file_path: &PathBuf, max_retries: u32, delay: Duration) -> Result<()> {
@@ -118,7 +237,9 @@ fn remove_file_with_retries(file_path: &PathBuf, max_retries: u32, delay: Durati
 }
 
 fn decode_image(file: &FileItem) -> Result<DynamicImage> {
+    // Go by the content, the extension may not match it.
     let img = match ImageReader::open(file.tmp_path.as_path())
+        .and_then(ImageReader::with_guessed_format)
         .map_err(MediaError::IoError)?
         .decode()
     {
@@ -131,15 +252,11 @@ fn decode_image(file: &FileItem) -> Result<DynamicImage> {
             let img_reader = File::open(file.tmp_path.as_path()).map_err(MediaError::IoError)?;
             let mut decoder = Decoder::new(BufReader::new(img_reader));
             let pixels = decoder.decode().map_err(MediaError::ImageDecodeError)?;
-            let img = DynamicImage::ImageRgb8(
-                image::ImageBuffer::from_raw(
-                    decoder.info().unwrap().width as u32,
-                    decoder.info().unwrap().height as u32,
-                    pixels,
-                )
-                .unwrap(),
-            );
-            img
+            let info = decoder.info().context("No image info after decoding")?;
+            DynamicImage::ImageRgb8(
+                image::ImageBuffer::from_raw(info.width as u32, info.height as u32, pixels)
+                    .context("Decoded pixels do not match the image size")?,
+            )
         }
     };
     Ok(img)
@@ -147,7 +264,7 @@ fn decode_image(file: &FileItem) -> Result<DynamicImage> {
 
 pub fn process_image(
     file: &FileItem,
-    imgsz: usize,
+    format: FrameFormat,
     quality: f32,
     parser: &mut MediaParser,
     resizer: &mut Resizer,
@@ -155,24 +272,13 @@ pub fn process_image(
 ) -> Result<()> {
     let frame_data = match decode_image(file) {
         Ok(img) => {
-            let webp: Option<Vec<u8>> = match resize_encode(&img, imgsz as u32, quality, resizer) {
-                Ok(webp) => Some(webp),
-                Err(_e) => None,
-            };
+            let image: Option<Vec<u8>> = resize_encode(&img, format, quality, resizer).ok();
             let shoot_time: Option<DateTime<Local>> =
-                match get_image_date(parser, file.tmp_path.as_path()) {
-                    Ok(shoot_time) => Some(shoot_time),
-                    Err(_e) => None,
-                };
-            if webp.is_none() {
-                WebpItem::ErrFile(ErrFile {
-                    file: file.clone(),
-                    error: MediaError::WebpEncodeError("Failed to encode image".to_string()).into(),
-                })
-            } else {
-                let webp = webp.unwrap();
+                get_image_date(parser, file.tmp_path.as_path()).ok();
+            if let Some(image) = image {
                 let frame_data = Frame {
-                    webp,
+                    image,
+                    encoding: format.encoding,
                     file: file.clone(),
                     width: img.width() as usize,
                     height: img.height() as usize,
@@ -181,6 +287,11 @@ pub fn process_image(
                     shoot_time,
                 };
                 WebpItem::Frame(frame_data)
+            } else {
+                WebpItem::ErrFile(ErrFile {
+                    file: file.clone(),
+                    error: anyhow::anyhow!("Failed to encode image as {}", format.encoding.name()),
+                })
             }
         }
         Err(error) => WebpItem::ErrFile(ErrFile {
@@ -197,10 +308,11 @@ pub fn process_image(
 
 fn resize_encode(
     img: &DynamicImage,
-    imgsz: u32,
+    format: FrameFormat,
     quality: f32,
     resizer: &mut Resizer,
 ) -> Result<Vec<u8>> {
+    let imgsz = format.imgsz as u32;
     // Get the dimensions of the original image
     let (width, height) = img.dimensions();
     let mut resized_width = imgsz;
@@ -221,49 +333,37 @@ fn resize_encode(
 
     let resize_option = ResizeOptions::new().resize_alg(ResizeAlg::Nearest);
 
-    resizer
-        .resize(img, &mut resized_img, &resize_option)
-        .unwrap();
-
-    let encoder = Encoder::from_image(&resized_img);
+    resizer.resize(img, &mut resized_img, &resize_option)?;
 
-    match encoder {
-        Ok(encoder) => {
-            let webp = encoder.encode(quality);
-            let data = (&*webp).to_vec();
-            Ok(data)
-        }
-        Err(e) => {
-            error!("Failed to encode image: {:?}", e);
-            Err(MediaError::WebpEncodeError(e.to_string()).into())
-        }
-    }
+    format.encoding.encode(&resized_img, quality)
 }
 
 pub fn process_video(
     file: &FileItem,
-    imgsz: usize,
+    format: FrameFormat,
     quality: f32,
     iframe: bool,
     max_frames: Option<usize>,
     array_q_s: Sender<WebpItem>,
 ) -> Result<()> {
-    let video_path = file.tmp_path.to_string_lossy();
-    let input = create_ffmpeg_iter(&video_path, imgsz, iframe)?;
+    let input = create_ffmpeg_iter(&file.tmp_path, format.imgsz, iframe)?;
 
-    handle_ffmpeg_output(input, array_q_s, file, quality, max_frames)?;
+    handle_ffmpeg_output(input, array_q_s, file, format.encoding, quality, max_frames)?;
 
     Ok(())
 }
 
-fn create_ffmpeg_iter(video_path: &str, imgsz: usize, iframe: bool) -> Result<FfmpegIterator> {
+fn create_ffmpeg_iter(video_path: &Path, imgsz: usize, iframe: bool) -> Result<FfmpegIterator> {
     let mut ffmpeg_command = FfmpegCommand::new();
     if iframe {
         ffmpeg_command.args(["-skip_frame", "nokey"]);
     }
+    // `input` takes a `&str`, pass the path as is so names that are not
+    // UTF-8 reach ffmpeg unchanged.
     let iter = ffmpeg_command
-        .input(video_path)
-        .args(&[
+        .arg("-i")
+        .arg(video_path)
+        .args([
             "-an",
             "-vf",
             &format!(
@@ -287,6 +387,7 @@ fn handle_ffmpeg_output(
     input: FfmpegIterator,
     s: Sender<WebpItem>,
     file: &FileItem,
+    encoding: Encoding,
     quality: f32,
     max_frames: Option<usize>,
 ) -> Result<()> {
@@ -318,14 +419,11 @@ fn handle_ffmpeg_output(
             file: file.clone(),
             error,
         });
-        s.send(frame_data).expect("Send video frame failed");
+        s.send(frame_data).context("Send video frame failed")?;
     } else {
         let sampled_frames = sample_evenly(&frames, max_frames.unwrap_or(frames.len()));
 
-        let shoot_time: Option<DateTime<Local>> = match get_video_date(&file.tmp_path.as_path()) {
-            Ok(shoot_time) => Some(shoot_time),
-            Err(_e) => None,
-        };
+        let shoot_time: Option<DateTime<Local>> = get_video_date(file.tmp_path.as_path()).ok();
 
         //calculate ratio and padding
         let width = frames[0].width as usize;
@@ -333,23 +431,42 @@ fn handle_ffmpeg_output(
 
         let frames_length = sampled_frames.len();
 
+        let mut images = Vec::with_capacity(frames_length);
         for f in sampled_frames.into_iter() {
-            let encoder = Encoder::from_rgb(&f.data, f.width, f.height);
-
-            let webp = encoder.encode(quality);
-
-            let webp = (&*webp).to_vec();
+            let frame_num = f.frame_num as usize;
+            let image = RgbImage::from_raw(f.width, f.height, f.data)
+                .ok_or_else(|| MediaError::VideoDecodeError(file_path.clone()).into())
+                .and_then(|img| encoding.encode(&DynamicImage::ImageRgb8(img), quality));
+            let image = match image {
+                Ok(image) => image,
+                Err(error) => {
+                    error!("{:?}", error);
+                    s.send(WebpItem::ErrFile(ErrFile {
+                        file: file.clone(),
+                        error,
+                    }))
+                    .context("Send video frame failed")?;
+                    return Ok(());
+                }
+            };
+            images.push((frame_num, image));
+        }
 
+        // Send the frames only once all are encoded, so a file failing part
+        // way is exported as a single error instead of some frames and an
+        // error.
+        for (frame_num, image) in images {
             let frame_data = WebpItem::Frame(Frame {
-                webp,
+                image,
+                encoding,
                 file: file.clone(),
                 width,
                 height,
-                frame_index: f.frame_num as usize,
+                frame_index: frame_num,
                 total_frames: frames_length,
                 shoot_time,
             });
-            s.send(frame_data).expect("Send video frame failed");
+            s.send(frame_data).context("Send video frame failed")?;
         }
     }
     Ok(())
@@ -364,7 +481,10 @@ fn get_image_date(parser: &mut MediaParser, image: &Path) -> Result<DateTime<Loc
         .get(ExifTag::DateTimeOriginal)
         .or_else(|| exif.get(ExifTag::ModifyDate))
         .context("Neither DateTimeOriginal nor ModifyDate found")?;
-    let shoot_time = shoot_time.as_time().unwrap().with_timezone(&Local);
+    let shoot_time = shoot_time
+        .as_time()
+        .context("Shoot time is not a date")?
+        .with_timezone(&Local);
 
     Ok(shoot_time)
 }
@@ -408,3 +528,23 @@ fn get_video_date(video: &Path) -> Result<DateTime<Local>> {
         Ok(shoot_time)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_catch_panic() {
+        let file = FileItem::new(0, 1, PathBuf::from("cam/IMG_0001.JPG"), None);
+        assert!(catch_panic(&file, || Ok(())).is_ok());
+        let error = catch_panic(&file, || panic!("corrupt header")).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Panicked while processing cam/IMG_0001.JPG: corrupt header"
+        );
+        let error = catch_panic(&file, || panic!("{} frames", 3)).unwrap_err();
+        assert!(error.to_string().ends_with(": 3 frames"));
+        // Panics outside `catch_panic` reach the previous hook again.
+        assert!(!CATCHING_PANIC.with(Cell::get));
+    }
+}
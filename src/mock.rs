@@ -0,0 +1,564 @@
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use tokio::net::TcpListener;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::StreamExt;
+use tonic::codec::CompressionEncoding;
+use tonic::metadata::MetadataMap;
+use tonic::transport::{Identity, Server, ServerTlsConfig};
+use tonic::{Request, Response, Status, Streaming};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::backend::QuotaExceeded;
+use crate::export;
+use crate::md5rs::md5rs_server::{Md5rs, Md5rsServer};
+use crate::md5rs::{
+    AuthRequest, AuthResponse, Bbox, DetectBatchRequest, DetectBatchResponse, DetectRequest,
+    DetectResponse, ServerInfoRequest, ServerInfoResponse,
+};
+
+const LABELS: [&str; 4] = ["Animal", "Person", "Vehicle", "Blank"];
+
+/// Behaviour of the mock md5rs server.
+#[derive(Debug, Clone, Default)]
+pub struct MockConfig {
+    /// Access tokens accepted by `Auth`. Any token is accepted when empty.
+    pub tokens: HashSet<String>,
+    /// Access tokens whose daily quota is already used up.
+    pub exhausted_tokens: HashSet<String>,
+    /// Frames a token may submit before `Detect` fails with `RESOURCE_EXHAUSTED`.
+    pub quota: Option<usize>,
+    /// Delay before each response.
+    pub latency: Duration,
+    /// Fail every Detect stream with `UNAVAILABLE` after this many responses.
+    pub fail_after: Option<usize>,
+    /// Responses returned in turn instead of the deterministic ones.
+    pub script: Vec<ScriptedResponse>,
+    /// Lifetime of session tokens; streams using an expired token fail with
+    /// `UNAUTHENTICATED`.
+    pub session_ttl: Option<Duration>,
+    /// Longest frame side reported by `ServerInfo`, 1280 if unset.
+    pub imgsz: Option<u32>,
+    /// Encodings reported by `ServerInfo` and accepted by `Detect`, webp and
+    /// jpeg if empty.
+    pub encodings: Vec<String>,
+    /// Most frames per `DetectBatch` request, 16 if unset. 0 disables
+    /// `DetectBatch` like a server predating it.
+    pub max_batch: Option<usize>,
+}
+
+impl MockConfig {
+    fn max_batch(&self) -> usize {
+        self.max_batch.unwrap_or(16)
+    }
+
+    fn accepts(&self, encoding: &str) -> bool {
+        let encoding = if encoding.is_empty() {
+            "webp"
+        } else {
+            encoding
+        };
+        if self.encodings.is_empty() {
+            ["webp", "jpeg"].contains(&encoding)
+        } else {
+            self.encodings.iter().any(|e| e == encoding)
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct ScriptedResponse {
+    pub label: Vec<String>,
+    #[serde(default)]
+    pub bboxes: Vec<export::Bbox>,
+}
+
+/// Load scripted responses from a JSON array of `{"label": [...], "bboxes": [...]}`.
+pub fn load_script(path: &Path) -> Result<Vec<ScriptedResponse>> {
+    let json = std::fs::read_to_string(path)?;
+    Ok(serde_json::from_str(&json)?)
+}
+
+pub struct MockServer {
+    config: MockConfig,
+    // session token -> access token, expiry
+    sessions: Mutex<HashMap<String, (String, Option<Instant>)>>,
+    // access token -> frames answered
+    usage: Arc<Mutex<HashMap<String, usize>>>,
+    scripted: Arc<AtomicUsize>,
+}
+
+impl MockServer {
+    pub fn new(config: MockConfig) -> Self {
+        Self {
+            config,
+            sessions: Mutex::new(HashMap::new()),
+            usage: Arc::new(Mutex::new(HashMap::new())),
+            scripted: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Md5rs for MockServer {
+    type DetectStream = ReceiverStream<Result<DetectResponse, Status>>;
+    type DetectBatchStream = ReceiverStream<Result<DetectBatchResponse, Status>>;
+
+    async fn auth(&self, request: Request<AuthRequest>) -> Result<Response<AuthResponse>, Status> {
+        let token = request.into_inner().token;
+        if self.config.exhausted_tokens.contains(&token) {
+            return Err(quota_exceeded());
+        }
+        if !self.config.tokens.is_empty() && !self.config.tokens.contains(&token) {
+            info!("Rejected token");
+            return Ok(Response::new(AuthResponse {
+                success: false,
+                token: String::new(),
+                expires_in: 0,
+            }));
+        }
+        let session = Uuid::new_v4().to_string();
+        let expires_at = self.config.session_ttl.map(|ttl| Instant::now() + ttl);
+        self.sessions
+            .lock()
+            .unwrap()
+            .insert(session.clone(), (token, expires_at));
+        Ok(Response::new(AuthResponse {
+            success: true,
+            token: session,
+            expires_in: self
+                .config
+                .session_ttl
+                .map_or(0, |ttl| ttl.as_secs().max(1) as i64),
+        }))
+    }
+
+    async fn server_info(
+        &self,
+        _request: Request<ServerInfoRequest>,
+    ) -> Result<Response<ServerInfoResponse>, Status> {
+        let encodings = if self.config.encodings.is_empty() {
+            vec!["webp".to_string(), "jpeg".to_string()]
+        } else {
+            self.config.encodings.clone()
+        };
+        Ok(Response::new(ServerInfoResponse {
+            server_version: format!("mock {}", env!("CARGO_PKG_VERSION")),
+            model: "MegaDetector".to_string(),
+            model_version: "v5a".to_string(),
+            labels: LABELS.iter().map(|l| l.to_string()).collect(),
+            max_imgsz: self.config.imgsz.unwrap_or(1280) as i32,
+            encodings,
+            min_iou: 0.0,
+            max_iou: 1.0,
+            min_score: 0.0,
+            max_score: 1.0,
+            max_batch: self.config.max_batch() as i32,
+        }))
+    }
+
+    async fn detect(
+        &self,
+        request: Request<Streaming<DetectRequest>>,
+    ) -> Result<Response<Self::DetectStream>, Status> {
+        let mut responder = self
+            .responder(request.metadata())
+            .ok_or_else(|| Status::unauthenticated("Invalid session token"))?;
+        if responder.expired() {
+            return Err(Status::unauthenticated("Session expired"));
+        }
+        let mut inbound = request.into_inner();
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+
+        tokio::spawn(async move {
+            while let Some(request) = inbound.next().await {
+                let request = match request {
+                    Ok(request) => request,
+                    Err(status) => {
+                        warn!("Detect stream error: {}", status);
+                        break;
+                    }
+                };
+                if !responder.config.latency.is_zero() {
+                    tokio::time::sleep(responder.config.latency).await;
+                }
+                let response = match responder.fault(&request) {
+                    Some(status) => Err(status),
+                    None => Ok(responder.respond(&request)),
+                };
+                let failed = response.is_err();
+                if tx.send(response).await.is_err() || failed {
+                    break;
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn detect_batch(
+        &self,
+        request: Request<Streaming<DetectBatchRequest>>,
+    ) -> Result<Response<Self::DetectBatchStream>, Status> {
+        let max_batch = self.config.max_batch();
+        if max_batch == 0 {
+            return Err(Status::unimplemented("DetectBatch is disabled"));
+        }
+        let mut responder = self
+            .responder(request.metadata())
+            .ok_or_else(|| Status::unauthenticated("Invalid session token"))?;
+        if responder.expired() {
+            return Err(Status::unauthenticated("Session expired"));
+        }
+        let mut inbound = request.into_inner();
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+
+        tokio::spawn(async move {
+            while let Some(batch) = inbound.next().await {
+                let batch = match batch {
+                    Ok(batch) => batch,
+                    Err(status) => {
+                        warn!("DetectBatch stream error: {}", status);
+                        break;
+                    }
+                };
+                if batch.frames.len() > max_batch {
+                    let _ = tx
+                        .send(Err(Status::invalid_argument(format!(
+                            "Batch of {} frames exceeds {}",
+                            batch.frames.len(),
+                            max_batch
+                        ))))
+                        .await;
+                    break;
+                }
+                if !responder.config.latency.is_zero() {
+                    tokio::time::sleep(responder.config.latency).await;
+                }
+                // Frames answered before a failure are still returned.
+                let mut results = Vec::new();
+                let mut failure = None;
+                for frame in &batch.frames {
+                    if let Some(status) = responder.fault(frame) {
+                        failure = Some(status);
+                        break;
+                    }
+                    results.push(responder.respond(frame));
+                }
+                if !results.is_empty()
+                    && tx.send(Ok(DetectBatchResponse { results })).await.is_err()
+                {
+                    break;
+                }
+                if let Some(status) = failure {
+                    let _ = tx.send(Err(status)).await;
+                    break;
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+}
+
+impl MockServer {
+    /// A responder for a stream authorized by the session in `metadata`,
+    /// `None` for unknown sessions.
+    fn responder(&self, metadata: &MetadataMap) -> Option<Responder> {
+        let session = metadata
+            .get("authorization")
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+        let (token, expires_at) = self.sessions.lock().unwrap().get(session).cloned()?;
+        Some(Responder {
+            config: self.config.clone(),
+            usage: Arc::clone(&self.usage),
+            scripted: Arc::clone(&self.scripted),
+            token,
+            expires_at,
+            answered: 0,
+        })
+    }
+}
+
+/// Answers the frames of one Detect or DetectBatch stream.
+struct Responder {
+    config: MockConfig,
+    usage: Arc<Mutex<HashMap<String, usize>>>,
+    scripted: Arc<AtomicUsize>,
+    token: String,
+    expires_at: Option<Instant>,
+    answered: usize,
+}
+
+impl Responder {
+    fn expired(&self) -> bool {
+        self.expires_at.is_some_and(|at| Instant::now() >= at)
+    }
+
+    /// The error to fail the stream with instead of answering `request`,
+    /// counting the frame against the quota otherwise.
+    fn fault(&self, request: &DetectRequest) -> Option<Status> {
+        if !self.config.accepts(&request.encoding) {
+            return Some(Status::invalid_argument(format!(
+                "Unsupported encoding {}",
+                request.encoding
+            )));
+        }
+        if self.expired() {
+            return Some(Status::unauthenticated("Session expired"));
+        }
+        if self.config.fail_after.is_some_and(|n| self.answered >= n) {
+            return Some(Status::unavailable("Injected stream failure"));
+        }
+        if !take_quota(&self.usage, &self.token, self.config.quota) {
+            return Some(quota_exceeded());
+        }
+        None
+    }
+
+    fn respond(&mut self, request: &DetectRequest) -> DetectResponse {
+        let response = if self.config.script.is_empty() {
+            deterministic_response(request)
+        } else {
+            let i = self.scripted.fetch_add(1, Ordering::SeqCst) % self.config.script.len();
+            scripted_response(request, &self.config.script[i])
+        };
+        self.answered += 1;
+        response
+    }
+}
+
+/// Quotas are daily and reset at midnight UTC.
+fn quota_exceeded() -> Status {
+    let now = chrono::Utc::now();
+    let midnight = (now.date_naive() + chrono::Days::new(1))
+        .and_hms_opt(0, 0, 0)
+        .unwrap()
+        .and_utc();
+    QuotaExceeded {
+        resets_in: (midnight - now).to_std().ok(),
+    }
+    .to_status()
+}
+
+/// Count one frame against the token's quota, returning false once it is used up.
+fn take_quota(usage: &Mutex<HashMap<String, usize>>, token: &str, quota: Option<usize>) -> bool {
+    let Some(quota) = quota else {
+        return true;
+    };
+    let mut usage = usage.lock().unwrap();
+    let used = usage.entry(token.to_string()).or_insert(0);
+    if *used >= quota {
+        return false;
+    }
+    *used += 1;
+    true
+}
+
+/// Derive a stable detection from the image bytes, so the same frame always
+/// gets the same answer.
+fn deterministic_response(request: &DetectRequest) -> DetectResponse {
+    let hash = fnv1a(&request.image);
+    let class = (hash % LABELS.len() as u64) as usize;
+    let bboxs = if LABELS[class] == "Blank" {
+        Vec::new()
+    } else {
+        let x1 = (hash >> 8 & 0xff) as f32 / 512.0;
+        let y1 = (hash >> 16 & 0xff) as f32 / 512.0;
+        vec![Bbox {
+            x1,
+            y1,
+            x2: x1 + 0.5,
+            y2: y1 + 0.5,
+            class: class as i32,
+            score: 0.5 + (hash >> 24 & 0xff) as f32 / 512.0,
+        }]
+    };
+    DetectResponse {
+        uuid: request.uuid.clone(),
+        label: vec![LABELS[class].to_string()],
+        bboxs,
+    }
+}
+
+fn scripted_response(request: &DetectRequest, scripted: &ScriptedResponse) -> DetectResponse {
+    DetectResponse {
+        uuid: request.uuid.clone(),
+        label: scripted.label.clone(),
+        bboxs: scripted
+            .bboxes
+            .iter()
+            .map(|bbox| Bbox {
+                x1: bbox.x1,
+                y1: bbox.y1,
+                x2: bbox.x2,
+                y2: bbox.y2,
+                class: bbox.class as i32,
+                score: bbox.score,
+            })
+            .collect(),
+    }
+}
+
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Serve the mock md5rs service on `addr`, over TLS if an identity is given.
+pub async fn serve(addr: SocketAddr, config: MockConfig, identity: Option<Identity>) -> Result<()> {
+    serve_on(TcpListener::bind(addr).await?, config, identity).await
+}
+
+/// Serve the mock md5rs service on a bound `listener`, which takes
+/// connections from the moment it is bound.
+pub async fn serve_on(
+    listener: TcpListener,
+    config: MockConfig,
+    identity: Option<Identity>,
+) -> Result<()> {
+    let addr = listener.local_addr()?;
+    let incoming = async_stream::stream! {
+        loop {
+            yield listener.accept().await.map(|(stream, _)| stream);
+        }
+    };
+    let mut server = Server::builder();
+    if let Some(identity) = identity {
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        server = server.tls_config(ServerTlsConfig::new().identity(identity))?;
+    }
+    info!("Mock md5rs server listening on {}", addr);
+    server
+        .add_service(
+            Md5rsServer::new(MockServer::new(config))
+                .accept_compressed(CompressionEncoding::Gzip)
+                .accept_compressed(CompressionEncoding::Zstd)
+                .send_compressed(CompressionEncoding::Gzip)
+                .send_compressed(CompressionEncoding::Zstd),
+        )
+        .serve_with_incoming(incoming)
+        .await?;
+    Ok(())
+}
+
+/// Start a mock server on a free local port and return its address.
+#[cfg(test)]
+pub(crate) async fn spawn(config: MockConfig) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(serve_on(listener, config, None));
+    addr
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::md5rs::md5rs_client::Md5rsClient;
+
+    fn request(image: &[u8]) -> DetectRequest {
+        DetectRequest {
+            uuid: Uuid::new_v4().to_string(),
+            image: image.to_vec(),
+            width: 1,
+            height: 1,
+            iou: 0.45,
+            score: 0.2,
+            encoding: String::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mock_server_round_trip() {
+        let config = MockConfig {
+            tokens: HashSet::from(["good".to_string()]),
+            quota: Some(2),
+            ..Default::default()
+        };
+        let addr = spawn(config).await;
+
+        let mut client = Md5rsClient::connect(format!("http://{}", addr))
+            .await
+            .unwrap();
+        let rejected = client
+            .auth(AuthRequest {
+                token: "bad".to_string(),
+            })
+            .await
+            .unwrap();
+        assert!(!rejected.into_inner().success);
+        let session = client
+            .auth(AuthRequest {
+                token: "good".to_string(),
+            })
+            .await
+            .unwrap()
+            .into_inner()
+            .token;
+
+        let frames = vec![request(b"a"), request(b"a"), request(b"b")];
+        let mut request = Request::new(tokio_stream::iter(frames));
+        request
+            .metadata_mut()
+            .insert("authorization", session.parse().unwrap());
+        let mut inbound = client.detect(request).await.unwrap().into_inner();
+
+        let first = inbound.message().await.unwrap().unwrap();
+        let second = inbound.message().await.unwrap().unwrap();
+        assert_eq!(first.label, second.label);
+        let status = inbound.message().await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+        let exceeded = QuotaExceeded::from_status(&status).unwrap();
+        assert!(exceeded.resets_in.unwrap() <= Duration::from_secs(24 * 60 * 60));
+    }
+
+    #[tokio::test]
+    async fn test_mock_server_batches() {
+        let config = MockConfig {
+            max_batch: Some(3),
+            ..Default::default()
+        };
+        let addr = spawn(config).await;
+
+        let mut client = Md5rsClient::connect(format!("http://{}", addr))
+            .await
+            .unwrap();
+        let session = client
+            .auth(AuthRequest {
+                token: "any".to_string(),
+            })
+            .await
+            .unwrap()
+            .into_inner()
+            .token;
+
+        let frames = vec![request(b"a"), request(b"b"), request(b"c")];
+        let uuids: Vec<String> = frames.iter().map(|f| f.uuid.clone()).collect();
+        let batches = vec![
+            DetectBatchRequest { frames },
+            DetectBatchRequest {
+                frames: vec![request(b"d"); 4],
+            },
+        ];
+        let mut request = Request::new(tokio_stream::iter(batches));
+        request
+            .metadata_mut()
+            .insert("authorization", session.parse().unwrap());
+        let mut inbound = client.detect_batch(request).await.unwrap().into_inner();
+
+        let batch = inbound.message().await.unwrap().unwrap();
+        let answered: Vec<String> = batch.results.into_iter().map(|r| r.uuid).collect();
+        assert_eq!(answered, uuids);
+        let status = inbound.message().await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    }
+}
@@ -0,0 +1,237 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::{Config, ExportFormat};
+
+/// Commented config file written by `config init`, holding the command line
+/// defaults.
+pub const DEFAULT_CONFIG: &str = r#"# md5rs-client configuration
+#
+# Pick a profile with `--config <this file> --profile <name>`, "default" is
+# used when --profile is not given. Keys have the names of the command line
+# options, and options given on the command line override the profile.
+
+[profiles.default]
+# md5rs servers, the work is spread over all of them
+url = ["https://md5rs.hinature.cn"]
+
+# File holding the access token. MD5RS_TOKEN takes precedence. A `token` key
+# works too but keeps the token in plain text in this file
+# token_file = "/home/me/.md5rs-token"
+
+# Detection thresholds
+iou = 0.45
+conf = 0.2
+
+# Result file format, "json" or "csv"
+export = "json"
+
+# Frames taken from each video, and whether only key frames are used
+max_frames = 3
+iframe_only = true
+# WebP/JPEG quality of the frames sent to the server
+quality = 70.0
+
+# Copy files to a local folder before decoding them, e.g. from a slow card
+# reader or network share, keeping at most buffer_size files there
+# buffer_path = "/tmp/md5rs-buffer"
+buffer_size = 20
+
+# A second profile for an on-prem server
+# [profiles.lan]
+# url = ["http://192.168.1.10:50051"]
+# conf = 0.1
+"#;
+
+#[derive(Error, Debug)]
+pub enum ProfileError {
+    #[error("Failed to read config file {path}: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("Invalid config file {path}: {source}")]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+
+    #[error("No profile {name} in config file {path}")]
+    UnknownProfile { name: String, path: PathBuf },
+
+    #[error("Config file {0} already exists, pass --force to overwrite it")]
+    Exists(PathBuf),
+
+    #[error("Failed to write config file {path}: {source}")]
+    Write {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+}
+
+/// Settings stored under `[profiles.<name>]` in a config file. Unset keys keep
+/// the command line value.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    /// Access token, prefer `token_file` on shared machines.
+    pub token: Option<String>,
+    /// File holding the access token.
+    pub token_file: Option<PathBuf>,
+    pub url: Option<Vec<String>>,
+    pub iou: Option<f32>,
+    pub conf: Option<f32>,
+    pub export: Option<ExportFormat>,
+    pub max_frames: Option<usize>,
+    pub iframe_only: Option<bool>,
+    pub quality: Option<f32>,
+    pub buffer_path: Option<String>,
+    pub buffer_size: Option<usize>,
+}
+
+impl Profile {
+    /// Copy the profile's settings into `config`, except for the options
+    /// `on_command_line` reports as given explicitly.
+    pub fn apply(&self, config: &mut Config, on_command_line: impl Fn(&str) -> bool) {
+        fn set<T: Clone + Into<U>, U>(
+            target: &mut U,
+            value: &Option<T>,
+            name: &str,
+            on_command_line: &impl Fn(&str) -> bool,
+        ) {
+            if let Some(value) = value {
+                if !on_command_line(name) {
+                    *target = value.clone().into();
+                }
+            }
+        }
+
+        set(&mut config.urls, &self.url, "url", &on_command_line);
+        set(&mut config.iou, &self.iou, "iou", &on_command_line);
+        set(&mut config.conf, &self.conf, "conf", &on_command_line);
+        set(&mut config.export, &self.export, "export", &on_command_line);
+        set(
+            &mut config.iframe_only,
+            &self.iframe_only,
+            "iframe_only",
+            &on_command_line,
+        );
+        set(
+            &mut config.quality,
+            &self.quality,
+            "quality",
+            &on_command_line,
+        );
+        set(
+            &mut config.buffer_size,
+            &self.buffer_size,
+            "buffer_size",
+            &on_command_line,
+        );
+        set(
+            &mut config.max_frames,
+            &self.max_frames,
+            "max_frames",
+            &on_command_line,
+        );
+        set(
+            &mut config.buffer_path,
+            &self.buffer_path,
+            "buffer_path",
+            &on_command_line,
+        );
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    #[serde(default)]
+    profiles: HashMap<String, Profile>,
+}
+
+/// Read profile `name` from the TOML config file at `path`.
+pub fn load(path: &Path, name: &str) -> Result<Profile, ProfileError> {
+    let text = fs::read_to_string(path).map_err(|source| ProfileError::Read {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    let mut file: ConfigFile = toml::from_str(&text).map_err(|source| ProfileError::Parse {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    file.profiles
+        .remove(name)
+        .ok_or_else(|| ProfileError::UnknownProfile {
+            name: name.to_string(),
+            path: path.to_path_buf(),
+        })
+}
+
+/// Write [`DEFAULT_CONFIG`] to `path`, refusing to replace an existing file
+/// unless `force` is set.
+pub fn init(path: &Path, force: bool) -> Result<(), ProfileError> {
+    if path.exists() && !force {
+        return Err(ProfileError::Exists(path.to_path_buf()));
+    }
+    fs::write(path, DEFAULT_CONFIG).map_err(|source| ProfileError::Write {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profiles_merge_with_command_line() {
+        let path = std::env::temp_dir().join(format!("md5rs-{}.toml", uuid::Uuid::new_v4()));
+        init(&path, false).unwrap();
+        assert!(matches!(init(&path, false), Err(ProfileError::Exists(_))));
+
+        // The written defaults change nothing.
+        let mut config = Config::default();
+        load(&path, "default")
+            .unwrap()
+            .apply(&mut config, |_| false);
+        let defaults = Config::default();
+        assert_eq!(config.urls, defaults.urls);
+        assert_eq!(config.max_frames, defaults.max_frames);
+        assert_eq!(
+            (config.iou, config.conf, config.quality),
+            (defaults.iou, defaults.conf, defaults.quality)
+        );
+        assert_eq!(config.export, defaults.export);
+        assert_eq!(config.buffer_size, defaults.buffer_size);
+
+        fs::write(
+            &path,
+            "[profiles.lan]\nurl = [\"http://10.0.0.2:50051\"]\nconf = 0.1\nexport = \"csv\"\nbuffer_path = \"/tmp/buf\"\n",
+        )
+        .unwrap();
+        let profile = load(&path, "lan").unwrap();
+        let mut config = Config::default();
+        profile.apply(&mut config, |name| name == "conf");
+        assert_eq!(config.urls, ["http://10.0.0.2:50051"]);
+        assert_eq!(config.conf, 0.2);
+        assert_eq!(config.export, ExportFormat::Csv);
+        assert_eq!(config.buffer_path.as_deref(), Some("/tmp/buf"));
+        assert!(matches!(
+            load(&path, "default"),
+            Err(ProfileError::UnknownProfile { .. })
+        ));
+
+        fs::write(&path, "[profiles.default]\ncnof = 0.1\n").unwrap();
+        assert!(matches!(
+            load(&path, "default"),
+            Err(ProfileError::Parse { .. })
+        ));
+        fs::remove_file(&path).unwrap();
+    }
+}
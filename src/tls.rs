@@ -0,0 +1,518 @@
+use std::future::Future;
+use std::io;
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{self, Poll};
+
+use anyhow::{Context, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use hyper_util::rt::TokioIo;
+use ring::digest::{digest, SHA256};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use rustls_native_certs::load_native_certs;
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use thiserror::Error;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+use tonic::transport::{Certificate, ClientTlsConfig, Identity, Uri};
+use tracing::{error, warn};
+use url::Url;
+use webpki::EndEntityCert;
+
+use crate::proxy::Proxy;
+use crate::Config;
+
+const BUNDLED_CERT: &str = "certs/cert.pem";
+
+#[derive(Error, Debug)]
+pub enum TlsError {
+    #[error("Invalid pin {0}, expected sha256/<base64 SPKI hash> or a hex SHA-256 certificate fingerprint")]
+    InvalidPin(String),
+
+    #[error("Server certificate chain does not match pin {pins}, server presented {presented}. Run `md5rs-client pin` to inspect it")]
+    PinMismatch { pins: String, presented: String },
+
+    #[error("Certificate pinning requires an https:// URL")]
+    PlaintextPin,
+}
+
+/// Where the client gets the certificates it trusts for the md5rs server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TlsRoots {
+    /// The operating system's root store.
+    System,
+    /// `certs/cert.pem` shipped next to the executable, holding the ISRG
+    /// roots that issue the md5rs.hinature.cn certificate.
+    Bundled,
+    /// A PEM bundle supplied by the user.
+    CaFile(String),
+}
+
+/// How the channel to a server is secured.
+pub enum ChannelSecurity {
+    /// A plaintext `http://` server.
+    Plaintext,
+    /// TLS run by tonic, verified against the configured roots.
+    Tls(ClientTlsConfig),
+    /// TLS run by [`PinnedConnector`], which checks the pins on every
+    /// handshake, reconnects included.
+    Pinned(Arc<ClientConfig>),
+}
+
+/// Build the TLS settings for the server at `url`.
+pub fn channel_security(config: &Config, url_str: &str) -> Result<ChannelSecurity> {
+    let url = Url::parse(url_str)?;
+    if url.scheme() == "http" {
+        if !config.pins.is_empty() {
+            return Err(TlsError::PlaintextPin.into());
+        }
+        if config.client_cert.is_some() {
+            warn!("Ignoring client certificate for plaintext {}", url);
+        }
+        return Ok(ChannelSecurity::Plaintext);
+    }
+    let host = url
+        .host_str()
+        .ok_or_else(|| anyhow::anyhow!("No host in URL"))?;
+    let _ = rustls::crypto::ring::default_provider().install_default();
+
+    if !config.pins.is_empty() {
+        return Ok(ChannelSecurity::Pinned(pinned_client_config(config)?));
+    }
+
+    let tls = ClientTlsConfig::new().domain_name(host);
+    let tls = match &config.tls_roots {
+        TlsRoots::System => tls.ca_certificates(system_certificates()?),
+        TlsRoots::Bundled => {
+            let path = bundled_cert_path();
+            let pem = std::fs::read(&path)
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            tls.ca_certificate(Certificate::from_pem(pem))
+        }
+        TlsRoots::CaFile(path) => {
+            let pem = std::fs::read(path).with_context(|| format!("Failed to read {}", path))?;
+            tls.ca_certificate(Certificate::from_pem(pem))
+        }
+    };
+
+    let tls = match (&config.client_cert, &config.client_key) {
+        (Some(cert), Some(key)) => {
+            let cert = std::fs::read(cert).with_context(|| format!("Failed to read {}", cert))?;
+            let key = std::fs::read(key).with_context(|| format!("Failed to read {}", key))?;
+            tls.identity(Identity::from_pem(cert, key))
+        }
+        (None, None) => tls,
+        _ => return Err(client_cert_pair_error()),
+    };
+
+    Ok(ChannelSecurity::Tls(tls))
+}
+
+fn client_cert_pair_error() -> anyhow::Error {
+    anyhow::anyhow!("Client certificate and key must be given together")
+}
+
+/// rustls settings verifying the server against the configured roots and
+/// pins.
+fn pinned_client_config(config: &Config) -> Result<Arc<ClientConfig>> {
+    let roots = Arc::new(root_store(&config.tls_roots)?);
+    let verifier = Arc::new(PinVerifier {
+        roots: WebPkiServerVerifier::builder(roots).build()?,
+        pins: config.pins.clone(),
+    });
+    let builder = ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(verifier);
+    let mut tls = match (&config.client_cert, &config.client_key) {
+        (Some(cert), Some(key)) => {
+            let chain = CertificateDer::pem_file_iter(cert)
+                .with_context(|| format!("Failed to read {}", cert))?
+                .collect::<Result<Vec<_>, _>>()?;
+            let key = PrivateKeyDer::from_pem_file(key)
+                .with_context(|| format!("Failed to read {}", key))?;
+            builder.with_client_auth_cert(chain, key)?
+        }
+        (None, None) => builder.with_no_client_auth(),
+        _ => return Err(client_cert_pair_error()),
+    };
+    tls.alpn_protocols = vec![b"h2".to_vec()];
+    Ok(Arc::new(tls))
+}
+
+/// Verifies the server chain against the roots, then requires one of the pins
+/// to match a certificate in it.
+#[derive(Debug)]
+struct PinVerifier {
+    roots: Arc<WebPkiServerVerifier>,
+    pins: Vec<String>,
+}
+
+impl ServerCertVerifier for PinVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.roots.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        )?;
+        let chain: Vec<CertificateDer<'_>> = std::iter::once(end_entity)
+            .chain(intermediates)
+            .cloned()
+            .collect();
+        match verify_pins(&self.pins, &chain) {
+            Ok(()) => Ok(verified),
+            Err(e) => {
+                error!("{:#}", e);
+                Err(rustls::Error::General(format!("{:#}", e)))
+            }
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.roots.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.roots.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.roots.supported_verify_schemes()
+    }
+}
+
+/// The `http://` form of `url` with an explicit port, for an endpoint whose
+/// TLS is run by [`PinnedConnector`] rather than tonic.
+pub fn pinned_endpoint_uri(url: &str) -> Result<String> {
+    let url = Url::parse(url)?;
+    let host = url
+        .host_str()
+        .ok_or_else(|| anyhow::anyhow!("No host in URL"))?;
+    Ok(format!(
+        "http://{}:{}",
+        host,
+        url.port_or_known_default().unwrap_or(443)
+    ))
+}
+
+/// Opens connections to the server, through a proxy if given, and runs the
+/// TLS handshake with [`ChannelSecurity::Pinned`] settings.
+#[derive(Clone)]
+pub struct PinnedConnector {
+    tls: TlsConnector,
+    server_name: ServerName<'static>,
+    proxy: Option<Proxy>,
+}
+
+impl PinnedConnector {
+    pub fn new(tls: Arc<ClientConfig>, url: &str, proxy: Option<Proxy>) -> Result<Self> {
+        let url = Url::parse(url)?;
+        let host = url
+            .host_str()
+            .ok_or_else(|| anyhow::anyhow!("No host in URL"))?
+            .trim_matches(|c| c == '[' || c == ']');
+        Ok(Self {
+            tls: TlsConnector::from(tls),
+            server_name: ServerName::try_from(host.to_string())?,
+            proxy,
+        })
+    }
+}
+
+impl tower_service::Service<Uri> for PinnedConnector {
+    type Response = TokioIo<TlsStream<tokio::net::TcpStream>>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<Self::Response>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let connector = self.clone();
+        Box::pin(async move {
+            let host = uri
+                .host()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No host in URL"))?
+                .trim_matches(|c| c == '[' || c == ']')
+                .to_string();
+            let port = uri.port_u16().unwrap_or(443);
+            let stream = match &connector.proxy {
+                Some(proxy) => proxy.connect(&host, port).await?,
+                None => tokio::net::TcpStream::connect((host.as_str(), port)).await?,
+            };
+            let stream = connector
+                .tls
+                .connect(connector.server_name.clone(), stream)
+                .await?;
+            Ok(TokioIo::new(stream))
+        })
+    }
+}
+
+fn system_certificates() -> Result<Vec<Certificate>> {
+    let native_certs = load_native_certs();
+    for e in native_certs.errors {
+        warn!("Failed to load a system certificate: {}", e);
+    }
+    native_certs
+        .certs
+        .iter()
+        .map(|cert| Ok(Certificate::from_pem(cert_to_pem(cert)?)))
+        .collect()
+}
+
+/// Look for the bundled certificate next to the executable first, then in the
+/// working directory.
+fn bundled_cert_path() -> PathBuf {
+    std::env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(|dir| dir.join(BUNDLED_CERT)))
+        .filter(|path| path.exists())
+        .unwrap_or_else(|| Path::new(BUNDLED_CERT).to_path_buf())
+}
+
+/// Handshake with the server and return the certificate chain it presents,
+/// verified against `root_store`.
+pub fn fetch_certificate_chain(
+    url_str: &str,
+    root_store: RootCertStore,
+    proxy: Option<&Proxy>,
+) -> Result<Vec<CertificateDer<'static>>> {
+    let _ = rustls::crypto::ring::default_provider().install_default();
+    // Parse the URL to extract domain
+    let url = Url::parse(url_str)?;
+    let domain_str = url
+        .host_str()
+        .ok_or_else(|| anyhow::anyhow!("No host in URL"))?;
+    let domain = domain_str.to_string();
+    let port = url.port().unwrap_or(443);
+
+    let server_name = ServerName::try_from(domain.clone())?;
+
+    let config = ClientConfig::builder()
+        .with_root_certificates(root_store)
+        .with_no_client_auth();
+
+    let mut client = rustls::ClientConnection::new(Arc::new(config), server_name)?;
+
+    let mut stream = match proxy {
+        Some(proxy) => proxy.connect_blocking(&domain, port)?,
+        None => TcpStream::connect(format!("{}:{}", domain, port))?,
+    };
+    while client.is_handshaking() {
+        client.complete_io(&mut stream)?;
+    }
+
+    // Get certificate information
+    match client.peer_certificates() {
+        Some(certs) => Ok(certs.iter().map(|cert| cert.clone().into_owned()).collect()),
+        None => {
+            error!("No certificate found for {}", domain);
+            Ok(Vec::new())
+        }
+    }
+}
+
+/// Root certificates used to verify pinned connections and the chains
+/// fetched by `pin` and `check`.
+fn root_store(roots: &TlsRoots) -> Result<RootCertStore> {
+    let mut root_store = RootCertStore::empty();
+    let certs: Vec<CertificateDer<'static>> = match roots {
+        TlsRoots::System => load_native_certs().certs,
+        TlsRoots::Bundled => {
+            CertificateDer::pem_file_iter(bundled_cert_path())?.collect::<Result<_, _>>()?
+        }
+        TlsRoots::CaFile(path) => CertificateDer::pem_file_iter(path)?.collect::<Result<_, _>>()?,
+    };
+    for cert in certs {
+        root_store.add(cert)?;
+    }
+    Ok(root_store)
+}
+
+/// SHA-256 fingerprints of one certificate in a server chain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fingerprint {
+    /// Hash of the whole certificate, as `AB:CD:...` like `openssl x509 -fingerprint -sha256`.
+    pub certificate: String,
+    /// Hash of the subject public key info, as `sha256/<base64>`.
+    pub spki: String,
+}
+
+impl Fingerprint {
+    pub fn of(cert: &CertificateDer<'_>) -> Result<Self> {
+        let certificate = digest(&SHA256, cert.as_ref())
+            .as_ref()
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect::<Vec<_>>()
+            .join(":");
+        let end_entity = EndEntityCert::try_from(cert)
+            .map_err(|e| anyhow::anyhow!("Failed to parse certificate: {:?}", e))?;
+        let spki = end_entity.subject_public_key_info();
+        let spki = format!("sha256/{}", BASE64.encode(digest(&SHA256, spki.as_ref())));
+        Ok(Self { certificate, spki })
+    }
+
+    /// Whether `pin` names this certificate, either by SPKI hash
+    /// (`sha256/<base64>`) or by certificate fingerprint in hex.
+    pub fn matches(&self, pin: &str) -> Result<bool> {
+        if pin.starts_with("sha256/") {
+            return Ok(pin == self.spki);
+        }
+        let hex = pin.replace(':', "").to_uppercase();
+        if hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(TlsError::InvalidPin(pin.to_string()).into());
+        }
+        Ok(hex == self.certificate.replace(':', ""))
+    }
+}
+
+/// Fetch the chain presented by the server at `url` and fingerprint it,
+/// leaf first.
+pub fn server_fingerprints(
+    url: &str,
+    roots: &TlsRoots,
+    proxy: Option<&str>,
+) -> Result<Vec<Fingerprint>> {
+    let proxy = Proxy::for_target(proxy, &Url::parse(url)?)?;
+    let chain = fetch_certificate_chain(url, root_store(roots)?, proxy.as_ref())?;
+    chain.iter().map(Fingerprint::of).collect()
+}
+
+/// Fetch the chain presented by the server at `url`, verify it against the
+/// roots and pins in `config` and fingerprint it, leaf first.
+pub fn verify_server_chain(config: &Config, url: &str) -> Result<Vec<Fingerprint>> {
+    let proxy = Proxy::for_target(config.proxy.as_deref(), &Url::parse(url)?)?;
+    let chain = fetch_certificate_chain(url, root_store(&config.tls_roots)?, proxy.as_ref())?;
+    if !config.pins.is_empty() {
+        verify_pins(&config.pins, &chain)?;
+    }
+    chain.iter().map(Fingerprint::of).collect()
+}
+
+/// Fail unless one of `pins` matches a certificate in `chain`.
+fn verify_pins(pins: &[String], chain: &[CertificateDer<'_>]) -> Result<()> {
+    let fingerprints = chain
+        .iter()
+        .map(Fingerprint::of)
+        .collect::<Result<Vec<_>>>()?;
+    for pin in pins {
+        for fingerprint in &fingerprints {
+            if fingerprint.matches(pin)? {
+                return Ok(());
+            }
+        }
+    }
+    Err(TlsError::PinMismatch {
+        pins: pins.join(", "),
+        presented: fingerprints
+            .iter()
+            .map(|f| f.spki.clone())
+            .collect::<Vec<_>>()
+            .join(", "),
+    }
+    .into())
+}
+
+fn cert_to_pem(cert: &CertificateDer<'_>) -> Result<String> {
+    // Convert the certificate data to base64
+    let b64_data = BASE64.encode(cert.as_ref());
+
+    // Format with PEM headers and line wrapping (64 characters per line)
+    let mut pem_content = String::from("-----BEGIN CERTIFICATE-----\n");
+
+    // Add base64 data with line breaks every 64 characters
+    for chunk in b64_data.as_bytes().chunks(64) {
+        pem_content.push_str(&String::from_utf8_lossy(chunk));
+        pem_content.push('\n');
+    }
+
+    pem_content.push_str("-----END CERTIFICATE-----\n");
+
+    Ok(pem_content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fingerprint_matches_pins() {
+        let cert = CertificateDer::pem_file_iter(BUNDLED_CERT)
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap();
+        // ISRG Root X1.
+        let fingerprint = Fingerprint::of(&cert).unwrap();
+        assert_eq!(
+            fingerprint.spki,
+            "sha256/C5+lpZ7tcVwmwQIMcRtPbsQtWLABXhQzejna0wHFr8M="
+        );
+        assert!(fingerprint
+            .matches("96bcec06264976f37460779acf28c5a7cfe8a3c0aae11a8ffcee05c0bddf08c6")
+            .unwrap());
+        assert!(fingerprint
+            .matches("96:BC:EC:06:26:49:76:F3:74:60:77:9A:CF:28:C5:A7:CF:E8:A3:C0:AA:E1:1A:8F:FC:EE:05:C0:BD:DF:08:C6")
+            .unwrap());
+        assert!(!fingerprint.matches("sha256/AAAA").unwrap());
+        assert!(fingerprint.matches("not-a-pin").is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_pin_checked_by_channel_handshake() {
+        use crate::backend::{DetectionBackend, GrpcBackend};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!(
+            "https://localhost:{}",
+            listener.local_addr().unwrap().port()
+        );
+        let identity = Identity::from_pem(
+            std::fs::read("input/tls/server.pem").unwrap(),
+            std::fs::read("input/tls/server.key").unwrap(),
+        );
+        tokio::spawn(crate::mock::serve_on(
+            listener,
+            Default::default(),
+            Some(identity),
+        ));
+
+        let mut config = Config {
+            tls_roots: TlsRoots::CaFile("input/tls/ca.pem".to_string()),
+            pins: vec!["sha256/x39uUnqNUb1muzyUaH9rJudqM3oICCu6o2FZI4BxmP8=".to_string()],
+            ..Default::default()
+        };
+        let mut backend = GrpcBackend::connect(&config, &url).await.unwrap();
+        assert!(backend.auth("token").await.is_ok());
+
+        config.pins = vec!["sha256/SX5yC9tzT1iHTFRpMbMpAfacLCewTYUjVPh0GRUzB2c=".to_string()];
+        let error = GrpcBackend::connect(&config, &url).await.err().unwrap();
+        assert!(format!("{:?}", error).contains("does not match pin"));
+    }
+}
@@ -0,0 +1,155 @@
+use std::fmt;
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+
+use crate::profile::Profile;
+
+/// Environment variable holding the access token.
+pub const TOKEN_ENV: &str = "MD5RS_TOKEN";
+
+#[derive(Error, Debug)]
+pub enum TokenError {
+    #[error(
+        "No access token, set {TOKEN_ENV}, pass --token-file or add a token to the config profile"
+    )]
+    Missing,
+
+    #[error("Failed to read token file {path}: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("Token file {0} is empty")]
+    Empty(PathBuf),
+}
+
+/// An access token that never shows up in `Debug` output.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Token(String);
+
+impl Token {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Token(***)")
+    }
+}
+
+impl Deref for Token {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for Token {
+    fn from(token: String) -> Self {
+        Token(token)
+    }
+}
+
+impl From<&str> for Token {
+    fn from(token: &str) -> Self {
+        Token(token.to_string())
+    }
+}
+
+/// Find the access token, in order of priority: `MD5RS_TOKEN`, `token_file`,
+/// the profile's token or token file, and last the `--token` flag.
+pub fn resolve(
+    token_file: Option<&Path>,
+    profile: &Profile,
+    flag: Option<&str>,
+) -> Result<Token, TokenError> {
+    resolve_with(std::env::var(TOKEN_ENV).ok(), token_file, profile, flag)
+}
+
+fn resolve_with(
+    env: Option<String>,
+    token_file: Option<&Path>,
+    profile: &Profile,
+    flag: Option<&str>,
+) -> Result<Token, TokenError> {
+    if let Some(token) = env.filter(|t| !t.trim().is_empty()) {
+        return Ok(token.trim().into());
+    }
+    if let Some(path) = token_file.or(profile.token_file.as_deref()) {
+        return read_token_file(path);
+    }
+    profile
+        .token
+        .as_deref()
+        .or(flag)
+        .map(str::trim)
+        .filter(|t| !t.is_empty())
+        .map(Token::from)
+        .ok_or(TokenError::Missing)
+}
+
+fn read_token_file(path: &Path) -> Result<Token, TokenError> {
+    let text = fs::read_to_string(path).map_err(|source| TokenError::Read {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    let token = text.trim();
+    if token.is_empty() {
+        return Err(TokenError::Empty(path.to_path_buf()));
+    }
+    Ok(token.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_priority() {
+        let dir = std::env::temp_dir().join(format!("md5rs-token-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("token");
+        fs::write(&file, "from-file\n").unwrap();
+        let profile = Profile {
+            token: Some("from-profile".to_string()),
+            ..Default::default()
+        };
+
+        let resolved = |env: Option<&str>, file: Option<&Path>, profile: &Profile| {
+            resolve_with(env.map(String::from), file, profile, Some("from-flag"))
+                .map(|t| t.as_str().to_string())
+        };
+        assert_eq!(
+            resolved(Some("from-env"), Some(&file), &profile).unwrap(),
+            "from-env"
+        );
+        assert_eq!(
+            resolved(Some(""), Some(&file), &profile).unwrap(),
+            "from-file"
+        );
+        assert_eq!(resolved(None, None, &profile).unwrap(), "from-profile");
+        assert_eq!(
+            resolved(None, None, &Profile::default()).unwrap(),
+            "from-flag"
+        );
+        assert!(matches!(
+            resolve_with(None, None, &Profile::default(), None),
+            Err(TokenError::Missing)
+        ));
+
+        fs::write(&file, " \n").unwrap();
+        assert!(matches!(
+            resolved(None, Some(&file), &profile),
+            Err(TokenError::Empty(_))
+        ));
+        assert_eq!(format!("{:?}", Token::from("secret")), "Token(***)");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,20 +1,18 @@
-use std::collections::HashSet;
-use std::io::Write;
-use std::path::{Path, PathBuf};
-use std::sync::Arc;
-use std::net::TcpStream;
-
-use anyhow::Result;
-use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
-use rustls::ClientConfig;
-use rustls::RootCertStore;
-use rustls_native_certs::load_native_certs;
-use rustls_pki_types::{CertificateDer, ServerName};
+use std::collections::{BTreeMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ring::digest::{digest, SHA256};
 use serde::{Deserialize, Serialize};
-use tracing::error;
-use url::Url;
+use tracing::warn;
 use walkdir::{DirEntry, WalkDir};
 
+/// Per-folder settings file, applying to every file beneath its folder.
+pub const FOLDER_SETTINGS_FILE: &str = ".md5rs.toml";
+
 pub fn sample_evenly<T: Clone>(list: &[T], sample_size: usize) -> Vec<T> {
     let len = list.len();
     if sample_size == 0 || len == 0 {
@@ -30,13 +28,73 @@ pub fn sample_evenly<T: Clone>(list: &[T], sample_size: usize) -> Vec<T> {
     sampled_elements
 }
 
+/// Overrides read from `.md5rs.toml` files, e.g. for video-only cameras. A
+/// deeper file overrides the keys it sets.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FolderSettings {
+    pub conf: Option<f32>,
+    pub max_frames: Option<usize>,
+    pub iframe_only: Option<bool>,
+}
+
+impl FolderSettings {
+    fn merge(self, deeper: FolderSettings) -> FolderSettings {
+        FolderSettings {
+            conf: deeper.conf.or(self.conf),
+            max_frames: deeper.max_frames.or(self.max_frames),
+            iframe_only: deeper.iframe_only.or(self.iframe_only),
+        }
+    }
+
+    fn read(dir: &Path) -> Result<Option<FolderSettings>> {
+        let path = dir.join(FOLDER_SETTINGS_FILE);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let text = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let settings =
+            toml::from_str(&text).with_context(|| format!("Invalid {}", path.display()))?;
+        Ok(Some(settings))
+    }
+}
+
+impl Eq for FolderSettings {}
+
+impl Hash for FolderSettings {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.conf.map(f32::to_bits).hash(state);
+        self.max_frames.hash(state);
+        self.iframe_only.hash(state);
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Hash)]
 pub struct FileItem {
     pub folder_id: usize,
     pub file_id: usize,
+    /// Path below the processed folder with `/` separators, exported as
+    /// `file_path` so results can be moved between machines.
+    #[serde(rename = "file_path")]
+    pub relative_path: String,
+    /// Path of the file on this machine, exported as `absolute_path` when asked
+    /// for.
+    #[serde(
+        rename = "absolute_path",
+        default,
+        skip_serializing_if = "is_empty_path",
+        serialize_with = "serialize_lossy"
+    )]
     pub file_path: PathBuf,
-    #[serde(skip_serializing)]
+    #[serde(skip_serializing, default)]
     pub tmp_path: PathBuf,
+    /// Container read from the first bytes of the file, `None` when they were
+    /// not recognised or not read yet and the file goes by its extension.
+    #[serde(default)]
+    pub container: Option<Container>,
+    #[serde(skip, default)]
+    pub settings: FolderSettings,
 }
 
 impl Eq for FileItem {}
@@ -52,136 +110,986 @@ impl FileItem {
             Some(tmp_path) => Self {
                 folder_id,
                 file_id,
+                relative_path: slash_path(&file_path),
                 file_path,
-                tmp_path: tmp_path,
+                tmp_path,
+                container: None,
+                settings: FolderSettings::default(),
             },
             None => Self {
                 folder_id,
                 file_id,
+                relative_path: slash_path(&file_path),
                 file_path: file_path.clone(),
                 tmp_path: file_path,
+                container: None,
+                settings: FolderSettings::default(),
             },
         }
     }
 }
 
-fn is_skip(entry: &DirEntry) -> bool {
-    let skip_dirs = ["Animal", "Person", "Vehicle", "Blank"];
-    entry
-        .file_name()
-        .to_str()
-        .map(|s| {
-            skip_dirs.contains(&s) || s.starts_with('.') || s == "result.csv" || s == "result.json"
+impl FileItem {
+    /// Whether the file is decoded as a photo or a video, by its content when
+    /// the container was recognised.
+    pub fn kind(&self) -> Option<MediaKind> {
+        self.container
+            .map(Container::kind)
+            .or_else(|| MediaKind::of(&self.file_path))
+    }
+
+    /// Read the container from the first bytes of the file if indexing went
+    /// by its extension.
+    pub fn identify(&mut self) {
+        if self.container.is_none() {
+            self.container = Container::sniff(&self.tmp_path).ok().flatten();
+        }
+    }
+}
+
+fn is_empty_path(path: &Path) -> bool {
+    path.as_os_str().is_empty()
+}
+
+/// Unlike the derived `PathBuf` serializer, does not fail on names that are
+/// not valid UTF-8.
+fn serialize_lossy<S: serde::Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&path.to_string_lossy())
+}
+
+/// `path` with `/` separators on every platform.
+pub fn slash_path(path: &Path) -> String {
+    let mut slashed = String::new();
+    for component in path.components() {
+        match component {
+            Component::RootDir => slashed.push('/'),
+            component => {
+                if !slashed.is_empty() && !slashed.ends_with('/') {
+                    slashed.push('/');
+                }
+                slashed.push_str(&component.as_os_str().to_string_lossy());
+            }
+        }
+    }
+    slashed
+}
+
+/// Which files indexing picks up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Discovery {
+    /// Globs a file's path relative to its root folder must match, any
+    /// photo or video when empty.
+    pub include: Vec<String>,
+    /// Globs of files and folders to leave out, relative to the root folder.
+    pub exclude: Vec<String>,
+    /// Deepest level searched, 1 for the files directly in a root folder.
+    pub max_depth: Option<usize>,
+    pub follow_symlinks: bool,
+    /// Smallest file size in bytes.
+    pub min_size: Option<u64>,
+    /// Largest file size in bytes.
+    pub max_size: Option<u64>,
+    /// Names of folders never searched.
+    pub skip_dirs: Vec<String>,
+}
+
+/// Folders the organize script sorts results into.
+pub const DEFAULT_SKIP_DIRS: [&str; 4] = ["Animal", "Person", "Vehicle", "Blank"];
+
+impl Default for Discovery {
+    fn default() -> Self {
+        Self {
+            include: Vec::new(),
+            exclude: Vec::new(),
+            max_depth: None,
+            follow_symlinks: false,
+            min_size: None,
+            max_size: None,
+            skip_dirs: DEFAULT_SKIP_DIRS.iter().map(|d| d.to_string()).collect(),
+        }
+    }
+}
+
+/// [`Discovery`] with its globs compiled.
+struct Rules<'a> {
+    discovery: &'a Discovery,
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+}
+
+impl<'a> Rules<'a> {
+    fn new(discovery: &'a Discovery) -> Result<Self> {
+        let include = match discovery.include.is_empty() {
+            true => None,
+            false => Some(glob_set(&discovery.include)?),
+        };
+        Ok(Rules {
+            discovery,
+            include,
+            exclude: glob_set(&discovery.exclude)?,
         })
-        .unwrap_or(false)
-}
-
-pub fn index_files_and_folders(folder_path: &PathBuf) -> HashSet<FileItem> {
-    let mut folder_id: usize = 0;
-    let mut file_id: usize = 0;
-    let mut file_paths = HashSet::new();
-
-    for entry in WalkDir::new(folder_path)
-        .sort_by_file_name()
-        .into_iter()
-        .filter_entry(|e| !is_skip(e))
-    {
-        let entry = entry.unwrap();
-        if entry.file_type().is_dir() {
-            folder_id += 1;
-        } else if entry.file_type().is_file() {
-            if is_video_photo(entry.path()) {
-                file_paths.insert(FileItem::new(
-                    folder_id,
-                    file_id,
-                    entry.path().to_path_buf(),
-                    None,
-                ));
-                file_id += 1;
+    }
+
+    /// Whether to leave out `entry` and, for a folder, everything beneath it.
+    fn skips(&self, entry: &DirEntry, root: &Path) -> bool {
+        if entry.depth() == 0 {
+            return false;
+        }
+        let name = entry.file_name().to_string_lossy();
+        name.starts_with('.')
+            || name == "result.csv"
+            || name == "result.json"
+            || (entry.file_type().is_dir() && self.discovery.skip_dirs.iter().any(|d| *d == name))
+            || self
+                .exclude
+                .is_match(entry.path().strip_prefix(root).unwrap_or(entry.path()))
+    }
+
+    /// Whether to index the file at `path`, `relative` to its root folder.
+    fn keeps(&self, path: &Path, relative: &Path) -> bool {
+        if self.exclude.is_match(relative)
+            || !self.include.as_ref().map_or(true, |i| i.is_match(relative))
+        {
+            return false;
+        }
+        if self.discovery.min_size.is_none() && self.discovery.max_size.is_none() {
+            return true;
+        }
+        let Ok(len) = std::fs::metadata(path).map(|m| m.len()) else {
+            return false;
+        };
+        self.discovery.min_size.map_or(true, |min| len >= min)
+            && self.discovery.max_size.map_or(true, |max| len <= max)
+    }
+}
+
+fn glob_set(patterns: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern).with_context(|| format!("Invalid glob {}", pattern))?);
+    }
+    Ok(builder.build()?)
+}
+
+/// How ids are given to files and folders the id map does not know yet.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IdScheme {
+    /// Numbered in the order they are found.
+    #[default]
+    Sequential,
+    /// A hash of the path relative to the parent of the root folder, the same
+    /// on every machine and unaffected by other files.
+    PathHash,
+}
+
+/// Ids given out in earlier runs, keyed by the path relative to the parent of
+/// the root folder with `/` separators, e.g. `card_01/DCIM/IMG_0001.JPG`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct IdMap {
+    #[serde(default)]
+    pub folders: BTreeMap<String, usize>,
+    #[serde(default)]
+    pub files: BTreeMap<String, usize>,
+}
+
+impl IdMap {
+    /// Read the map at `path`, or start an empty one if there is none yet.
+    pub fn load(path: &Path) -> Result<IdMap> {
+        if !path.exists() {
+            return Ok(IdMap::default());
+        }
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read id map {}", path.display()))?;
+        serde_json::from_str(&text).with_context(|| format!("Invalid id map {}", path.display()))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("Failed to write id map {}", path.display()))
+    }
+}
+
+/// Hashed ids are kept below 2^53 so they survive a round trip through JSON
+/// numbers in other tools.
+const MAX_HASH_ID: u64 = (1 << 53) - 1;
+
+fn path_hash(key: &str) -> usize {
+    let digest = digest(&SHA256, key.as_bytes());
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&digest.as_ref()[..8]);
+    (u64::from_be_bytes(bytes) & MAX_HASH_ID) as usize
+}
+
+/// A root folder and the label telling it apart from the other roots.
+#[derive(Clone, Copy)]
+struct Root<'a> {
+    path: &'a Path,
+    label: &'a str,
+}
+
+/// Label each root with the fewest trailing components of its path that
+/// differ from those of every other root: `card` on its own, but `x/card`
+/// and `y/card` for two cards of that name.
+fn root_labels(roots: &[PathBuf]) -> Vec<String> {
+    let names: Vec<Vec<String>> = roots
+        .iter()
+        .map(|root| {
+            root.components()
+                .filter_map(|c| match c {
+                    Component::Normal(name) => Some(name.to_string_lossy().into_owned()),
+                    _ => None,
+                })
+                .collect()
+        })
+        .collect();
+    let label = |names: &[String], n: usize| names[names.len().saturating_sub(n)..].join("/");
+    names
+        .iter()
+        .map(|own| {
+            let mut n = 1;
+            while n < own.len()
+                && names
+                    .iter()
+                    .any(|other| other != own && label(other, n) == label(own, n))
+            {
+                n += 1;
             }
+            label(own, n)
+        })
+        .collect()
+}
+
+/// Key of `path` in the [`IdMap`]: the label of `root` followed by the path
+/// below it, or the whole path for listed files.
+fn id_key(root: Option<Root>, path: &Path) -> String {
+    let (label, relative) = match root {
+        Some(root) => (root.label, path.strip_prefix(root.path).unwrap_or(path)),
+        None => ("", path),
+    };
+    let mut parts: Vec<_> = relative
+        .components()
+        .filter(|c| !matches!(c, Component::RootDir))
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect();
+    if !label.is_empty() {
+        parts.insert(0, label.into());
+    }
+    parts.join("/")
+}
+
+/// Hands out unique ids of one kind, reusing those of the id map.
+struct Ids {
+    scheme: IdScheme,
+    known: BTreeMap<String, usize>,
+    used: HashSet<usize>,
+    next: usize,
+}
+
+impl Ids {
+    fn new(scheme: IdScheme, known: BTreeMap<String, usize>, first: usize) -> Self {
+        let used: HashSet<usize> = known.values().copied().collect();
+        let next = used.iter().max().map_or(first, |max| max + 1);
+        Ids {
+            scheme,
+            known,
+            used,
+            next,
         }
     }
 
-    file_paths
+    fn assign(&mut self, key: String) -> usize {
+        if let Some(id) = self.known.get(&key) {
+            return *id;
+        }
+        let mut id = match self.scheme {
+            IdScheme::Sequential => self.next,
+            IdScheme::PathHash => path_hash(&key),
+        };
+        // Hash collisions move on to the next free id.
+        while !self.used.insert(id) {
+            id += 1;
+        }
+        self.next = self.next.max(id + 1);
+        self.known.insert(key, id);
+        id
+    }
 }
 
-fn is_video_photo(path: &Path) -> bool {
-    if let Some(extension) = path.extension() {
-        match extension.to_str().unwrap().to_lowercase().as_str() {
-            "mp4" | "avi" | "mkv" | "mov" => true,
-            "jpg" | "jpeg" | "png" => true,
-            _ => false,
+pub fn index_files_and_folders(folder_path: &PathBuf) -> Result<HashSet<FileItem>> {
+    index_inputs(
+        std::slice::from_ref(folder_path),
+        &[],
+        &Discovery::default(),
+        IdScheme::Sequential,
+        &mut IdMap::default(),
+    )
+}
+
+/// Index every root folder and listed file. Ids stay unique across all of
+/// them and a file reached twice is only indexed once. Listed files are
+/// matched against the globs with their path as given. `id_map` supplies the
+/// ids of files and folders seen before and receives those of new ones.
+///
+/// The relative path of a file is taken below its root folder, prefixed with
+/// the root's label when there are several so it starts with the root
+/// folder's name, or as many trailing folders as set it apart from the other
+/// roots. Listed files keep their whole path.
+pub fn index_inputs(
+    folders: &[PathBuf],
+    files: &[PathBuf],
+    discovery: &Discovery,
+    scheme: IdScheme,
+    id_map: &mut IdMap,
+) -> Result<HashSet<FileItem>> {
+    let rules = Rules::new(discovery)?;
+    let mut index = Index {
+        folder_ids: Ids::new(scheme, std::mem::take(&mut id_map.folders), 1),
+        file_ids: Ids::new(scheme, std::mem::take(&mut id_map.files), 0),
+        files: HashSet::new(),
+        seen: HashSet::new(),
+        several_roots: folders.len() > 1,
+    };
+    for (folder, label) in folders.iter().zip(root_labels(folders)) {
+        let root = Root {
+            path: folder,
+            label: &label,
+        };
+        index.walk(root, &rules)?;
+    }
+    for file in files {
+        if !file.is_file() {
+            return Err(anyhow!("Listed file {} does not exist", file.display()));
+        }
+        if !rules.keeps(file, file) {
+            continue;
+        }
+        let Some(container) = identify(file) else {
+            warn!(
+                "Skipping listed file {}, not a photo or video",
+                file.display()
+            );
+            continue;
+        };
+        let parent = file.parent().unwrap_or(Path::new(""));
+        let folder_id = index.folder_ids.assign(id_key(None, parent));
+        index.add(None, file, folder_id, container, FolderSettings::default());
+    }
+    id_map.folders = index.folder_ids.known;
+    id_map.files = index.file_ids.known;
+    Ok(index.files)
+}
+
+struct Index {
+    folder_ids: Ids,
+    file_ids: Ids,
+    files: HashSet<FileItem>,
+    seen: HashSet<PathBuf>,
+    several_roots: bool,
+}
+
+impl Index {
+    fn add(
+        &mut self,
+        root: Option<Root>,
+        path: &Path,
+        folder_id: usize,
+        container: Option<Container>,
+        settings: FolderSettings,
+    ) {
+        if !self.seen.insert(path.to_path_buf()) {
+            return;
         }
+        let file_id = self.file_ids.assign(id_key(root, path));
+        let mut file = FileItem::new(folder_id, file_id, path.to_path_buf(), None);
+        if let Some(root) = root {
+            file.relative_path = if self.several_roots {
+                id_key(Some(root), path)
+            } else {
+                slash_path(path.strip_prefix(root.path).unwrap_or(path))
+            };
+        }
+        file.container = container;
+        file.settings = settings;
+        self.files.insert(file);
+    }
+
+    fn walk(&mut self, root: Root, rules: &Rules) -> Result<()> {
+        let folder_path = root.path;
+        // The folders above the current entry with their depth, id and
+        // settings.
+        let mut parents: Vec<(usize, usize, FolderSettings)> = Vec::new();
+
+        let mut walker = WalkDir::new(folder_path)
+            .sort_by_file_name()
+            .follow_links(rules.discovery.follow_symlinks);
+        if let Some(max_depth) = rules.discovery.max_depth {
+            walker = walker.max_depth(max_depth);
+        }
+        for entry in walker
+            .into_iter()
+            .filter_entry(|e| !rules.skips(e, folder_path))
+        {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    warn!("Skipping {}", e);
+                    continue;
+                }
+            };
+            while parents
+                .last()
+                .is_some_and(|(depth, _, _)| *depth >= entry.depth())
+            {
+                parents.pop();
+            }
+            let (folder_id, inherited) = parents
+                .last()
+                .map(|(_, id, settings)| (*id, *settings))
+                .unwrap_or_default();
+            if entry.file_type().is_dir() {
+                let id = self.folder_ids.assign(id_key(Some(root), entry.path()));
+                let settings = match FolderSettings::read(entry.path())? {
+                    Some(own) => inherited.merge(own),
+                    None => inherited,
+                };
+                parents.push((entry.depth(), id, settings));
+            } else if entry.file_type().is_file()
+                && rules.keeps(
+                    entry.path(),
+                    entry
+                        .path()
+                        .strip_prefix(folder_path)
+                        .unwrap_or(entry.path()),
+                )
+            {
+                if let Some(container) = identify(entry.path()) {
+                    self.add(Some(root), entry.path(), folder_id, container, inherited);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Read a list of files, one per line, from `path` or from stdin for `-`.
+/// Names that are not valid UTF-8 are kept as they are on Unix.
+pub fn read_file_list(path: &Path) -> Result<Vec<PathBuf>> {
+    let bytes = if path == Path::new("-") {
+        let mut bytes = Vec::new();
+        std::io::Read::read_to_end(&mut std::io::stdin(), &mut bytes)
+            .context("Failed to read the file list from stdin")?;
+        bytes
     } else {
-        false
+        std::fs::read(path)
+            .with_context(|| format!("Failed to read file list {}", path.display()))?
+    };
+    Ok(bytes
+        .split(|b| *b == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+        .filter(|line| !line.is_empty())
+        .map(path_from_bytes)
+        .collect())
+}
+
+#[cfg(unix)]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    use std::os::unix::ffi::OsStrExt;
+    PathBuf::from(std::ffi::OsStr::from_bytes(bytes))
+}
+
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(bytes).into_owned())
+}
+
+/// What a file holds, judged by its extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MediaKind {
+    Image,
+    Video,
+}
+
+impl MediaKind {
+    /// `None` for files without an extension or with one that is not a
+    /// supported photo or video, including extensions that are not UTF-8.
+    pub fn of(path: &Path) -> Option<MediaKind> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "jpg" | "jpeg" | "png" => Some(MediaKind::Image),
+            "mp4" | "avi" | "mkv" | "mov" => Some(MediaKind::Video),
+            _ => None,
+        }
     }
 }
 
-pub fn get_tls_certificate(url_str: &str) -> Result<String> {
-    let _ = rustls::crypto::ring::default_provider().install_default();
-    // Parse the URL to extract domain
-    let url = Url::parse(url_str)?;
-    let domain_str = url
-        .host_str()
-        .ok_or_else(|| anyhow::anyhow!("No host in URL"))?;
-    let domain = domain_str.to_string();
-    let port = url.port().unwrap_or(443);
+/// Extensions of the thumbnails and low resolution proxies cameras write next
+/// to their recordings, left out although they hold JPEG or MP4 data.
+const SIDECAR_EXTENSIONS: [&str; 2] = ["thm", "lrv"];
 
-    let server_name = ServerName::try_from(domain.clone())?;
+/// `ftyp` brands of HEIF and AVIF photos, which share the box layout of MP4.
+const IMAGE_BRANDS: [&[u8]; 10] = [
+    b"heic", b"heix", b"heim", b"heis", b"hevc", b"hevx", b"mif1", b"msf1", b"avif", b"avis",
+];
 
-    // Load root certificates from the system
-    let mut root_store = RootCertStore::empty();
-    let native_certs = load_native_certs();
-    for cert in native_certs.certs {
-        root_store.add(cert)?;
+/// Container format of a photo or video, recognised by its first bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Container {
+    Jpeg,
+    Png,
+    Avi,
+    /// ISO base media file, e.g. `.mp4` or `.3gp`.
+    Mp4,
+    /// QuickTime movie, the ISO base media flavour written as `.mov`.
+    Mov,
+    /// Matroska, including WebM.
+    Matroska,
+    /// MPEG program stream, e.g. the `.dat` clips of some trail cameras.
+    Mpeg,
+}
+
+impl Container {
+    pub const ALL: [Container; 7] = [
+        Container::Jpeg,
+        Container::Png,
+        Container::Avi,
+        Container::Mp4,
+        Container::Mov,
+        Container::Matroska,
+        Container::Mpeg,
+    ];
+
+    /// Name of the container in the export.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Container::Jpeg => "jpeg",
+            Container::Png => "png",
+            Container::Avi => "avi",
+            Container::Mp4 => "mp4",
+            Container::Mov => "mov",
+            Container::Matroska => "matroska",
+            Container::Mpeg => "mpeg",
+        }
     }
 
-    let config = ClientConfig::builder()
-        .with_root_certificates(root_store)
-        .with_no_client_auth();
+    pub fn from_name(name: &str) -> Option<Container> {
+        Container::ALL.into_iter().find(|c| c.name() == name)
+    }
 
-    let mut client = rustls::ClientConnection::new(Arc::new(config), server_name)?;
+    pub fn kind(self) -> MediaKind {
+        match self {
+            Container::Jpeg | Container::Png => MediaKind::Image,
+            _ => MediaKind::Video,
+        }
+    }
 
-    let mut stream = TcpStream::connect(format!("{}:{}", domain, port))?;
-    let mut tls_stream = rustls::Stream::new(&mut client, &mut stream);
+    /// Read the container of the file at `path` from its first bytes.
+    pub fn sniff(path: &Path) -> std::io::Result<Option<Container>> {
+        let mut head = Vec::with_capacity(12);
+        std::fs::File::open(path)?.take(12).read_to_end(&mut head)?;
+        Ok(Container::from_head(&head))
+    }
 
-    // Write HTTP request
-    tls_stream.write_all(
-        format!(
-            "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
-            url.path(),
-            domain
-        )
-        .as_bytes(),
-    )?;
-    let mut pem = String::new();
-    // Get certificate information
-    if let Some(certs) = tls_stream.conn.peer_certificates() {
-        for cert in certs.iter() {
-            let pem_content = cert_to_pem(cert)?;
-            pem.push_str(&pem_content);
+    fn from_head(head: &[u8]) -> Option<Container> {
+        match head {
+            [0xff, 0xd8, 0xff, ..] => Some(Container::Jpeg),
+            [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a, ..] => Some(Container::Png),
+            [b'R', b'I', b'F', b'F', _, _, _, _, b'A', b'V', b'I', b' ', ..] => {
+                Some(Container::Avi)
+            }
+            [_, _, _, _, b'f', b't', b'y', b'p', brand @ ..] => match brand.get(..4) {
+                Some(b"qt  ") => Some(Container::Mov),
+                Some(brand) if IMAGE_BRANDS.contains(&brand) => None,
+                _ => Some(Container::Mp4),
+            },
+            // QuickTime files from before the `ftyp` box.
+            [_, _, _, _, b'm', b'o', b'o', b'v', ..]
+            | [_, _, _, _, b'm', b'd', b'a', b't', ..]
+            | [_, _, _, _, b'w', b'i', b'd', b'e', ..] => Some(Container::Mov),
+            [0x1a, 0x45, 0xdf, 0xa3, ..] => Some(Container::Matroska),
+            [0x00, 0x00, 0x01, 0xba, ..] => Some(Container::Mpeg),
+            _ => None,
         }
-    } else {
-        error!("No certificate found for {}", domain);
     }
+}
 
-    Ok(pem)
+/// Whether the file at `path` is a photo or video to process, with its
+/// container when it had to be read to tell. Files with a photo or video
+/// extension are taken without reading them, their container is read when
+/// they are decoded. Other files give `None`.
+fn identify(path: &Path) -> Option<Option<Container>> {
+    let extension = path
+        .extension()
+        .map(|e| e.to_string_lossy().to_ascii_lowercase());
+    if extension.is_some_and(|e| SIDECAR_EXTENSIONS.contains(&e.as_str())) {
+        return None;
+    }
+    if MediaKind::of(path).is_some() {
+        return Some(None);
+    }
+    Container::sniff(path).ok().flatten().map(Some)
 }
 
-fn cert_to_pem(cert: &CertificateDer<'_>) -> Result<String> {
-    // Convert the certificate data to base64
-    let b64_data = BASE64.encode(cert.as_ref());
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_folder_settings_apply_beneath() {
+        let root = std::env::temp_dir().join(format!("md5rs-index-{}", uuid::Uuid::new_v4()));
+        for dir in ["stills", "video", "video/deep", "video2"] {
+            std::fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        for file in [
+            "a.jpg",
+            "stills/b.jpg",
+            "video/c.mp4",
+            "video/deep/d.mp4",
+            "video2/e.mp4",
+        ] {
+            std::fs::write(root.join(file), b"").unwrap();
+        }
+        std::fs::write(root.join(FOLDER_SETTINGS_FILE), "conf = 0.3\n").unwrap();
+        std::fs::write(
+            root.join("video").join(FOLDER_SETTINGS_FILE),
+            "max_frames = 10\niframe_only = false\n",
+        )
+        .unwrap();
+        std::fs::write(
+            root.join("video/deep").join(FOLDER_SETTINGS_FILE),
+            "conf = 0.5\n",
+        )
+        .unwrap();
+
+        let files = index_files_and_folders(&root).unwrap();
+        let settings = |name: &str| {
+            files
+                .iter()
+                .find(|f| f.file_path.ends_with(name))
+                .unwrap()
+                .settings
+        };
+        let root_only = FolderSettings {
+            conf: Some(0.3),
+            ..Default::default()
+        };
+        assert_eq!(files.len(), 5);
+        assert_eq!(settings("a.jpg"), root_only);
+        assert_eq!(settings("b.jpg"), root_only);
+        assert_eq!(
+            settings("c.mp4"),
+            FolderSettings {
+                conf: Some(0.3),
+                max_frames: Some(10),
+                iframe_only: Some(false),
+            }
+        );
+        assert_eq!(
+            settings("d.mp4"),
+            FolderSettings {
+                conf: Some(0.5),
+                max_frames: Some(10),
+                iframe_only: Some(false),
+            }
+        );
+        assert_eq!(settings("e.mp4"), root_only);
+
+        std::fs::write(root.join("video2").join(FOLDER_SETTINGS_FILE), "cnof = 1").unwrap();
+        assert!(index_files_and_folders(&root).is_err());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_discovery_rules() {
+        let root = std::env::temp_dir().join(format!("md5rs-rules-{}", uuid::Uuid::new_v4()));
+        for dir in ["DCIM/100", "DCIM/thumbs", "Animal", "Extra"] {
+            std::fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        for (file, len) in [
+            ("top.jpg", 10),
+            ("DCIM/100/a.JPG", 10),
+            ("DCIM/100/b.mp4", 1000),
+            ("DCIM/thumbs/c.jpg", 10),
+            ("Animal/d.jpg", 10),
+            ("Extra/e.jpg", 10),
+        ] {
+            std::fs::write(root.join(file), vec![0u8; len]).unwrap();
+        }
+        let names = |discovery: &Discovery| {
+            let mut names: Vec<String> = index_inputs(
+                std::slice::from_ref(&root),
+                &[],
+                discovery,
+                IdScheme::Sequential,
+                &mut IdMap::default(),
+            )
+            .unwrap()
+            .iter()
+            .map(|f| {
+                f.file_path
+                    .file_name()
+                    .unwrap()
+                    .to_string_lossy()
+                    .into_owned()
+            })
+            .collect();
+            names.sort();
+            names
+        };
+
+        let defaults = Discovery::default();
+        assert_eq!(
+            names(&defaults),
+            ["a.JPG", "b.mp4", "c.jpg", "e.jpg", "top.jpg"]
+        );
+        assert_eq!(
+            names(&Discovery {
+                include: vec!["DCIM/**".to_string()],
+                exclude: vec!["**/thumbs".to_string()],
+                ..defaults.clone()
+            }),
+            ["a.JPG", "b.mp4"]
+        );
+        assert_eq!(
+            names(&Discovery {
+                max_depth: Some(1),
+                ..defaults.clone()
+            }),
+            ["top.jpg"]
+        );
+        assert_eq!(
+            names(&Discovery {
+                min_size: Some(100),
+                ..defaults.clone()
+            }),
+            ["b.mp4"]
+        );
+        assert_eq!(
+            names(&Discovery {
+                skip_dirs: vec!["Extra".to_string(), "thumbs".to_string()],
+                max_size: Some(100),
+                ..defaults.clone()
+            }),
+            ["a.JPG", "d.jpg", "top.jpg"]
+        );
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(root.join("Extra"), root.join("linked")).unwrap();
+            let followed = Discovery {
+                follow_symlinks: true,
+                include: vec!["linked/*".to_string()],
+                ..defaults.clone()
+            };
+            assert_eq!(names(&followed), ["e.jpg"]);
+            assert!(names(&Discovery {
+                follow_symlinks: false,
+                ..followed
+            })
+            .is_empty());
+        }
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_stable_ids() {
+        let parent = std::env::temp_dir().join(format!("md5rs-ids-{}", uuid::Uuid::new_v4()));
+        let root = parent.join("card_01");
+        std::fs::create_dir_all(root.join("cam_b")).unwrap();
+        for file in ["cam_b/b.jpg", "cam_b/c.jpg"] {
+            std::fs::write(root.join(file), b"").unwrap();
+        }
+        let ids = |scheme, id_map: &mut IdMap| {
+            index_inputs(
+                std::slice::from_ref(&root),
+                &[],
+                &Discovery::default(),
+                scheme,
+                id_map,
+            )
+            .unwrap()
+            .into_iter()
+            .map(|f| {
+                let name = f
+                    .file_path
+                    .file_name()
+                    .unwrap()
+                    .to_string_lossy()
+                    .into_owned();
+                (name, (f.folder_id, f.file_id))
+            })
+            .collect::<std::collections::HashMap<_, _>>()
+        };
+
+        let mut map = IdMap::default();
+        let hashed = ids(IdScheme::PathHash, &mut IdMap::default());
+        let sequential = ids(IdScheme::Sequential, &mut map);
+        assert_eq!(hashed["b.jpg"].1, path_hash("card_01/cam_b/b.jpg"));
+        assert_eq!(hashed["b.jpg"].0, path_hash("card_01/cam_b"));
+        assert_eq!(map.files["card_01/cam_b/c.jpg"], sequential["c.jpg"].1);
+
+        // A new folder and file sorting first shift neither scheme's ids.
+        std::fs::create_dir_all(root.join("cam_a")).unwrap();
+        std::fs::write(root.join("cam_a/a.jpg"), b"").unwrap();
+        std::fs::write(root.join("cam_b/a2.jpg"), b"").unwrap();
+        let path = parent.join("ids.json");
+        map.save(&path).unwrap();
+        let mut map = IdMap::load(&path).unwrap();
+        let sequential_again = ids(IdScheme::Sequential, &mut map);
+        let hashed_again = ids(IdScheme::PathHash, &mut IdMap::default());
+        for name in ["b.jpg", "c.jpg"] {
+            assert_eq!(sequential_again[name], sequential[name]);
+            assert_eq!(hashed_again[name], hashed[name]);
+        }
+        let file_ids: HashSet<usize> = sequential_again.values().map(|(_, id)| *id).collect();
+        assert_eq!(file_ids.len(), 4);
+        assert_eq!(map.files.len(), 4);
+        std::fs::remove_dir_all(&parent).unwrap();
+    }
+
+    #[test]
+    fn test_sequential_folder_ids() {
+        let root = std::env::temp_dir().join(format!("md5rs-folders-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(root.join("b")).unwrap();
+        for file in ["a.jpg", "b/b.jpg", "c.jpg"] {
+            std::fs::write(root.join(file), b"").unwrap();
+        }
+        let files = index_files_and_folders(&root).unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+        let folder_id = |name: &str| {
+            files
+                .iter()
+                .find(|f| f.relative_path == name)
+                .unwrap()
+                .folder_id
+        };
+        // c.jpg is found after folder b but belongs to the root; v0.1.3 and
+        // earlier gave it b's id.
+        assert_eq!(folder_id("a.jpg"), 1);
+        assert_eq!(folder_id("b/b.jpg"), 2);
+        assert_eq!(folder_id("c.jpg"), 1);
+    }
+
+    #[test]
+    fn test_same_named_roots() {
+        let parent = std::env::temp_dir().join(format!("md5rs-roots-{}", uuid::Uuid::new_v4()));
+        let roots = [parent.join("x/card"), parent.join("y/card")];
+        for root in &roots {
+            std::fs::create_dir_all(root.join("DCIM")).unwrap();
+            std::fs::write(root.join("DCIM/IMG_0001.JPG"), b"").unwrap();
+        }
+        assert_eq!(root_labels(&roots), ["x/card", "y/card"]);
+        assert_eq!(root_labels(&roots[..1]), ["card"]);
+
+        let mut map = IdMap::default();
+        let files = index_inputs(
+            &roots,
+            &[],
+            &Discovery::default(),
+            IdScheme::Sequential,
+            &mut map,
+        )
+        .unwrap();
+        assert_eq!(files.len(), 2);
+        let file_ids: HashSet<usize> = files.iter().map(|f| f.file_id).collect();
+        let folder_ids: HashSet<usize> = files.iter().map(|f| f.folder_id).collect();
+        assert_eq!(file_ids.len(), 2);
+        assert_eq!(folder_ids.len(), 2);
+        assert!(map.files.contains_key("x/card/DCIM/IMG_0001.JPG"));
+        assert!(map.files.contains_key("y/card/DCIM/IMG_0001.JPG"));
+        let mut relative: Vec<&str> = files.iter().map(|f| f.relative_path.as_str()).collect();
+        relative.sort();
+        assert_eq!(
+            relative,
+            ["x/card/DCIM/IMG_0001.JPG", "y/card/DCIM/IMG_0001.JPG"]
+        );
+        std::fs::remove_dir_all(&parent).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_non_utf8_names() {
+        use std::os::unix::ffi::OsStrExt;
+
+        // GBK names as written by older cameras and FAT cards.
+        let gbk = |bytes: &[u8]| PathBuf::from(std::ffi::OsStr::from_bytes(bytes));
+        let root = std::env::temp_dir().join(format!("md5rs-gbk-{}", uuid::Uuid::new_v4()));
+        let camera = root.join(gbk(b"\xcf\xe0\xbb\xfa"));
+        std::fs::create_dir_all(&camera).unwrap();
+        let image = camera.join(gbk(b"\xd5\xd5\xc6\xac.JPG"));
+        for file in [
+            image.clone(),
+            camera.join(gbk(b"\xca\xd3\xc6\xb5.mp4")),
+            camera.join("README"),
+            camera.join(gbk(b"clip.\xca\xd3")),
+        ] {
+            std::fs::write(file, b"").unwrap();
+        }
 
-    // Format with PEM headers and line wrapping (64 characters per line)
-    let mut pem_content = String::from("-----BEGIN CERTIFICATE-----\n");
+        assert_eq!(MediaKind::of(&image), Some(MediaKind::Image));
+        assert_eq!(MediaKind::of(Path::new("README")), None);
+        assert_eq!(MediaKind::of(&gbk(b"clip.\xca\xd3")), None);
+        let files = index_files_and_folders(&root).unwrap();
+        assert_eq!(files.len(), 2);
+        let item = files.iter().find(|f| f.file_path == image).unwrap();
+        assert!(item.relative_path.ends_with(".JPG"));
+        assert!(serde_json::to_string(item).is_ok());
 
-    // Add base64 data with line breaks every 64 characters
-    for chunk in b64_data.as_bytes().chunks(64) {
-        pem_content.push_str(&String::from_utf8_lossy(chunk));
-        pem_content.push('\n');
+        let list = root.join("list.txt");
+        let mut bytes = image.as_os_str().as_bytes().to_vec();
+        bytes.extend_from_slice(b"\r\n\n");
+        std::fs::write(&list, bytes).unwrap();
+        assert_eq!(read_file_list(&list).unwrap(), [image]);
+        std::fs::remove_dir_all(&root).unwrap();
     }
 
-    pem_content.push_str("-----END CERTIFICATE-----\n");
+    #[test]
+    fn test_identify_by_content() {
+        let root = std::env::temp_dir().join(format!("md5rs-sniff-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&root).unwrap();
+        let avi = b"RIFF\x00\x00\x00\x00AVI LIST";
+        for (name, head) in [
+            ("IMG_0001", &b"\xff\xd8\xff\xe0\x00\x10JFIF"[..]),
+            ("IMG_0002.JPG", &avi[..]),
+            ("IMG_0002.THM", &b"\xff\xd8\xff\xe0"[..]),
+            ("MOVI0003.dat", &b"\x00\x00\x01\xba\x44"[..]),
+            ("MOVI0004.MOV", &b"\x00\x00\x00\x14ftypqt  "[..]),
+            ("GX010005.MP4", &b"\x00\x00\x00\x18ftypmp42"[..]),
+            ("IMG_0006.HEIC", &b"\x00\x00\x00\x18ftypheic"[..]),
+            ("IMG_0007", &b"\x00\x00\x00\x1cftypavif"[..]),
+            ("notes.txt", &b"camera 7"[..]),
+            ("empty.mp4", &b""[..]),
+        ] {
+            std::fs::write(root.join(name), head).unwrap();
+        }
 
-    Ok(pem_content)
+        let files = index_files_and_folders(&root).unwrap();
+        // Only files without a photo or video extension are read to index
+        // them.
+        let indexed = |name: &str| files.iter().find(|f| f.relative_path == name).unwrap();
+        assert_eq!(indexed("IMG_0001").container, Some(Container::Jpeg));
+        assert_eq!(indexed("IMG_0002.JPG").container, None);
+        let found = |name: &str| {
+            files.iter().find(|f| f.relative_path == name).map(|f| {
+                let mut f = f.clone();
+                f.identify();
+                (f.container, f.kind())
+            })
+        };
+        assert_eq!(files.len(), 6);
+        assert_eq!(
+            found("IMG_0001"),
+            Some((Some(Container::Jpeg), Some(MediaKind::Image)))
+        );
+        assert_eq!(
+            found("IMG_0002.JPG"),
+            Some((Some(Container::Avi), Some(MediaKind::Video)))
+        );
+        assert_eq!(found("MOVI0003.dat").unwrap().0, Some(Container::Mpeg));
+        assert_eq!(found("MOVI0004.MOV").unwrap().0, Some(Container::Mov));
+        assert_eq!(found("GX010005.MP4").unwrap().0, Some(Container::Mp4));
+        // Kept by its extension, decoding it reports the error.
+        assert_eq!(found("empty.mp4"), Some((None, Some(MediaKind::Video))));
+        assert_eq!(Container::from_name("matroska"), Some(Container::Matroska));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
 }
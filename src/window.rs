@@ -0,0 +1,104 @@
+use std::time::Duration;
+
+/// Frames sent before the first round trips have been measured.
+const INITIAL_WINDOW: usize = 8;
+
+/// Limit on the number of frames awaiting a detection.
+///
+/// When adaptive, the limit grows by about one frame per round trip while the
+/// smoothed round-trip time stays close to the fastest one seen, and shrinks
+/// the same way once responses take twice as long, i.e. frames start queueing
+/// on the server or the link. It never drops below `min`, the frames a batch
+/// needs to fill up.
+#[derive(Debug, Clone)]
+pub struct Window {
+    limit: f64,
+    min: usize,
+    max: usize,
+    adaptive: bool,
+    min_rtt: Option<Duration>,
+    srtt: Option<Duration>,
+}
+
+impl Window {
+    pub fn new(max: usize, adaptive: bool, min: usize) -> Self {
+        let max = max.max(1);
+        let min = min.clamp(1, max);
+        let limit = if adaptive {
+            INITIAL_WINDOW.clamp(min, max)
+        } else {
+            max
+        };
+        Self {
+            limit: limit as f64,
+            min,
+            max,
+            adaptive,
+            min_rtt: None,
+            srtt: None,
+        }
+    }
+
+    /// Number of frames that may be awaiting a detection.
+    pub fn limit(&self) -> usize {
+        self.limit as usize
+    }
+
+    /// Smoothed round-trip time of recent frames.
+    pub fn srtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    /// Record the round-trip time of an answered frame.
+    pub fn on_response(&mut self, rtt: Duration) {
+        let srtt = match self.srtt {
+            Some(srtt) => srtt * 7 / 8 + rtt / 8,
+            None => rtt,
+        };
+        let min_rtt = self.min_rtt.map_or(rtt, |min_rtt| min_rtt.min(rtt));
+        self.srtt = Some(srtt);
+        self.min_rtt = Some(min_rtt);
+        if !self.adaptive {
+            return;
+        }
+        if srtt <= min_rtt * 3 / 2 {
+            self.limit = (self.limit + 1.0 / self.limit).min(self.max as f64);
+        } else if srtt >= min_rtt * 2 {
+            self.limit = (self.limit - 1.0 / self.limit).max(self.min as f64);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_window_follows_round_trip_time() {
+        let mut window = Window::new(16, true, 1);
+        assert_eq!(window.limit(), INITIAL_WINDOW);
+        for _ in 0..200 {
+            window.on_response(Duration::from_millis(100));
+        }
+        assert_eq!(window.limit(), 16);
+
+        for _ in 0..200 {
+            window.on_response(Duration::from_millis(400));
+        }
+        assert!(window.limit() < 16);
+
+        let mut fixed = Window::new(4, false, 1);
+        fixed.on_response(Duration::from_millis(100));
+        fixed.on_response(Duration::from_millis(900));
+        assert_eq!(fixed.limit(), 4);
+
+        // Room for a whole batch from the start and after slowing down.
+        let mut batched = Window::new(64, true, 16);
+        assert_eq!(batched.limit(), 16);
+        batched.on_response(Duration::from_millis(100));
+        for _ in 0..200 {
+            batched.on_response(Duration::from_millis(400));
+        }
+        assert_eq!(batched.limit(), 16);
+    }
+}